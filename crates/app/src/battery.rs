@@ -0,0 +1,93 @@
+//! 电池健康度估算
+use base::pb::tesla::*;
+
+/// 低于该温度(℃)的读数受电池温度影响较大, 不采用
+const MIN_OUTSIDE_TEMP: f64 = 5.0;
+/// SOC增量小于该值时不估算容量
+const MIN_SOC_DELTA: f64 = 10.0;
+
+fn median(mut v: Vec<f64>) -> f64 {
+    if v.is_empty() {
+        return 0.0;
+    }
+    v.sort_by(|a, b| a.total_cmp(b));
+    v[v.len() / 2]
+}
+
+/// 每次充电估算一个数据点, 低温或电池加热时返回None; 没有温度数据时只看电池加热
+pub fn estimate(charge: &HistoryCharge) -> Option<BatteryHealth> {
+    if charge.details.is_empty()
+        || charge.outside_temp.is_some_and(|t| t < MIN_OUTSIDE_TEMP)
+        || charge.details.iter().any(|cs| cs.battery_heater_on)
+    {
+        return None;
+    }
+    let full = |range: fn(&VehicleChargeState) -> f64| {
+        median(
+            charge
+                .details
+                .iter()
+                .filter(|cs| cs.usable_battery_level > 0.0 && range(cs) > 0.0)
+                .map(|cs| range(cs) / cs.usable_battery_level * 100.0)
+                .collect(),
+        )
+    };
+    let est_full_range = full(|cs| cs.battery_range);
+    if est_full_range <= 0.0 {
+        return None;
+    }
+    let soc_delta = charge.end_battery_level - charge.start_battery_level;
+    Some(BatteryHealth {
+        timestamp: charge.start_timestamp,
+        est_full_range,
+        est_full_ideal_range: full(|cs| cs.ideal_battery_range),
+        capacity: if soc_delta >= MIN_SOC_DELTA {
+            charge.charge_energy_added / soc_delta * 100.0
+        } else {
+            0.0
+        },
+        odometer: charge.odometer,
+        outside_temp: charge.outside_temp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn charge(outside_temp: Option<f64>, heater: bool) -> HistoryCharge {
+        let cs = |level: f64| VehicleChargeState {
+            usable_battery_level: level,
+            battery_level: level,
+            battery_range: level * 3.0,
+            ideal_battery_range: level * 3.2,
+            battery_heater_on: heater,
+            ..Default::default()
+        };
+        HistoryCharge {
+            start_timestamp: 1000,
+            start_battery_level: 30.0,
+            end_battery_level: 80.0,
+            charge_energy_added: 37.5,
+            outside_temp,
+            details: vec![cs(30.0), cs(55.0), cs(80.0)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn estimate_full_range_and_capacity() {
+        let h = estimate(&charge(Some(20.0), false)).unwrap();
+        assert!((h.est_full_range - 300.0).abs() < 1e-9);
+        assert!((h.est_full_ideal_range - 320.0).abs() < 1e-9);
+        assert!((h.capacity - 75.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skip_cold_readings() {
+        assert!(estimate(&charge(Some(-3.0), false)).is_none());
+        assert!(estimate(&charge(Some(20.0), true)).is_none());
+        // 没有温度数据不当作低温
+        assert!(estimate(&charge(None, false)).is_some());
+    }
+}
@@ -0,0 +1,149 @@
+//! 从区间数据中识别充电过程
+use crate::{geofence, supercharger, tariff};
+use base::pb::{
+    base::{Geofence, Tariff},
+    tesla::*,
+};
+
+fn is_charging(cs: &VehicleChargeState) -> bool {
+    cs.charging_state == "Charging" || cs.charger_power > 0.0
+}
+
+/// records需按时间排序, 位置取充电前最后一次stream推送的坐标
+pub fn detect_sessions(records: &[VehiclePeriodRecord]) -> Vec<HistoryCharge> {
+    let mut sessions = vec![];
+    let mut current: Option<HistoryCharge> = None;
+    let (mut latitude, mut longitude, mut odometer) = (0.0, 0.0, 0.0);
+    // 用于计算平均车外温度
+    let (mut temp_sum, mut temp_count) = (0.0, 0);
+    let finish = |mut session: HistoryCharge, temp_sum: f64, temp_count: i32| {
+        if temp_count > 0 {
+            session.outside_temp = Some(temp_sum / temp_count as f64);
+        }
+        session
+    };
+    for pr in records.iter() {
+        if let Some(ds) = pr.updates.iter().rev().find(|ds| ds.est_lat != 0.0) {
+            latitude = ds.est_lat;
+            longitude = ds.est_lng;
+            odometer = ds.odometer;
+        }
+        let snapshot = match pr.snapshot.as_ref() {
+            Some(s) => s,
+            None => continue,
+        };
+        if let Some(vs) = snapshot.vehicle_state.as_ref() {
+            odometer = vs.odometer;
+        }
+        let cs = match snapshot.charge_state.as_ref() {
+            Some(cs) => cs,
+            None => continue,
+        };
+        if is_charging(cs) {
+            let session = current.get_or_insert_with(|| HistoryCharge {
+                start_timestamp: pr.timestamp * 1000,
+                start_battery_level: cs.battery_level,
+                latitude,
+                longitude,
+                odometer,
+                ..Default::default()
+            });
+            session.end_timestamp = pr.timestamp * 1000;
+            session.end_battery_level = cs.battery_level;
+            session.charge_energy_added = cs.charge_energy_added;
+            session.details.push(cs.clone());
+            if let Some(climate) = snapshot.climate_state.as_ref() {
+                temp_sum += climate.outside_temp;
+                temp_count += 1;
+            }
+        } else if let Some(session) = current.take() {
+            sessions.push(finish(session, temp_sum, temp_count));
+            (temp_sum, temp_count) = (0.0, 0);
+        }
+    }
+    if let Some(session) = current {
+        sessions.push(finish(session, temp_sum, temp_count));
+    }
+    sessions
+}
+
+/// 快照不在充电时返回true, 没有充电状态时返回false
+fn not_charging(pr: &VehiclePeriodRecord) -> bool {
+    pr.snapshot
+        .as_ref()
+        .and_then(|s| s.charge_state.as_ref())
+        .map_or(false, |cs| !is_charging(cs))
+}
+
+/// 实时识别充电/行程用的区间数据, 推送只保留最后一个有坐标的
+pub fn window_record(pr: &VehiclePeriodRecord) -> VehiclePeriodRecord {
+    VehiclePeriodRecord {
+        timestamp: pr.timestamp,
+        snapshot: pr.snapshot.clone(),
+        updates: pr
+            .updates
+            .iter()
+            .rev()
+            .find(|ds| ds.est_lat != 0.0)
+            .cloned()
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// 去掉已经结束的充电过程, 保留最后一个不在充电的快照及之后的数据, 跨天的充电不会被截断
+pub fn trim_window(records: &mut Vec<VehiclePeriodRecord>) {
+    if let Some(i) = records.iter().rposition(not_charging) {
+        records.drain(..i);
+    }
+}
+
+/// 关联超充账单, 补全围栏和费用
+pub fn fill(
+    charges: &mut [HistoryCharge],
+    bills: &[SuperchargerBill],
+    geofences: &[Geofence],
+    tariffs: &[Tariff],
+) {
+    supercharger::link(charges, bills);
+    for charge in charges.iter_mut() {
+        charge.geofence = geofence::name_of(geofences, charge.latitude, charge.longitude);
+        tariff::apply(tariffs, charge);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, charging: bool) -> VehiclePeriodRecord {
+        VehiclePeriodRecord {
+            timestamp,
+            snapshot: Some(VehicleData {
+                charge_state: Some(VehicleChargeState {
+                    charging_state: if charging { "Charging" } else { "Stopped" }.into(),
+                    battery_level: timestamp as f64,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn window_keeps_ongoing_session() {
+        let mut window = vec![record(1, false), record(2, true), record(3, false)];
+        assert_eq!(detect_sessions(&window).len(), 1);
+        trim_window(&mut window);
+        assert_eq!(window.len(), 1);
+        window.extend([record(4, true), record(5, true)]);
+        trim_window(&mut window);
+        assert_eq!(window.len(), 3);
+        window.push(record(6, true));
+        let sessions = detect_sessions(&window);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].start_timestamp, 4000);
+        assert_eq!(sessions[0].end_battery_level, 6.0);
+    }
+}
@@ -0,0 +1,339 @@
+//! 命令行子命令, 用于无界面部署时的管理操作
+use crate::{geocoder, replay, reprocess, teslamate, Error};
+use base::pb::base::*;
+use base::pb::tesla::*;
+use db::pika::PikaConnection;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::Arc;
+use tesla_api::{ApiClient, TokenState};
+use tokio::sync::Mutex;
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// 启动监控和HTTP服务, 不指定子命令时默认执行
+    Serve,
+    /// 交互式设置access token和refresh token
+    Login,
+    /// 列出账号下的车辆
+    Vehicles,
+    /// 导出车辆的行程/充电/事件等数据为JSON, 不包含区间数据
+    Export {
+        #[clap(long)]
+        vehicle: i64,
+        /// 默认输出到stdout
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// 导入export导出的JSON
+    Import {
+        path: String,
+        /// 导入到指定的vehicle_id, 默认使用导出时的vehicle_id
+        #[clap(long)]
+        vehicle: Option<i64>,
+    },
+    /// 导入TeslaMate数据, path为pg_dump文件或csv导出目录
+    ImportTeslamate {
+        path: String,
+        /// 指定导入到的vehicle_id, 不指定时使用cars表中的vid
+        #[clap(long)]
+        vehicle_id: Option<i64>,
+    },
+    /// 回放--record录制的文件, 重新识别行程/充电等数据
+    Replay {
+        path: String,
+        /// 加速倍数, 0为不等待
+        #[clap(long, default_value_t = 1.0)]
+        speed: f64,
+        /// 回放结果写入的pika, 不能是配置中正在使用的
+        #[clap(long)]
+        pika: String,
+    },
+    /// 按保存的区间数据重新生成行程/充电/事件等数据, from/to为yyyymmdd
+    Reprocess {
+        #[clap(long)]
+        vehicle: i64,
+        #[clap(long)]
+        from: i32,
+        #[clap(long)]
+        to: i32,
+    },
+    /// 数据库维护
+    Db {
+        #[clap(subcommand)]
+        command: DbCommand,
+    },
+    /// access token管理
+    Token {
+        #[clap(subcommand)]
+        command: TokenCommand,
+    },
+    /// token有效期, 数据库连接和车辆状态
+    Status,
+}
+
+#[derive(clap::Subcommand)]
+pub enum DbCommand {
+    /// 检查车辆的全部数据能否解码
+    Verify {
+        #[clap(long)]
+        vehicle: i64,
+    },
+    /// 触发pika压缩
+    Compact,
+}
+
+#[derive(clap::Subcommand)]
+pub enum TokenCommand {
+    /// 立即刷新access token
+    Refresh,
+}
+
+/// export/import的文件格式
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Export {
+    pub vehicle_id: i64,
+    pub trips: Vec<Trip>,
+    pub charges: Vec<HistoryCharge>,
+    pub battery_health: Vec<BatteryHealth>,
+    pub supercharger_bills: Vec<SuperchargerBill>,
+    pub geofence_events: Vec<GeofenceEvent>,
+    pub software_updates: Vec<SoftwareUpdateRecord>,
+    pub tpms_daily: Vec<TpmsDaily>,
+    pub events: Vec<VehicleEvent>,
+}
+
+fn prompt(msg: &str) -> std::io::Result<String> {
+    print!("{msg}: ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+async fn export(pika: &mut PikaConnection, vid: i64) -> Result<Export, Error> {
+    Ok(Export {
+        vehicle_id: vid,
+        trips: pika.load_trips(vid).await?,
+        charges: pika.load_charges(vid).await?,
+        battery_health: pika.load_battery_health(vid).await?,
+        supercharger_bills: pika.load_supercharger_bills(vid).await?,
+        geofence_events: pika.load_geofence_events(vid).await?,
+        software_updates: pika.load_software_updates(vid).await?,
+        tpms_daily: pika.load_tpms_daily(vid).await?,
+        events: pika.load_vehicle_events(vid, 0, isize::MAX).await?.1,
+    })
+}
+
+/// 按key覆盖保存, 重复导入结果相同
+async fn import(pika: &mut PikaConnection, vid: i64, e: &Export) -> Result<(), Error> {
+    for t in e.trips.iter() {
+        pika.save_trip(vid, t).await?;
+    }
+    for c in e.charges.iter() {
+        pika.save_charge(vid, c).await?;
+    }
+    for h in e.battery_health.iter() {
+        pika.save_battery_health(vid, h).await?;
+    }
+    for b in e.supercharger_bills.iter() {
+        pika.save_supercharger_bill(vid, b).await?;
+    }
+    for g in e.geofence_events.iter() {
+        pika.save_geofence_event(vid, g).await?;
+    }
+    for u in e.software_updates.iter() {
+        pika.save_software_update(vid, u).await?;
+    }
+    for d in e.tpms_daily.iter() {
+        pika.save_tpms_daily(vid, d).await?;
+    }
+    for v in e.events.iter() {
+        pika.save_vehicle_event(vid, v).await?;
+    }
+    Ok(())
+}
+
+/// 除serve以外的子命令
+pub async fn run(
+    command: Command,
+    conf: &AppConfig,
+    token: Arc<Mutex<TokenState>>,
+) -> Result<(), Error> {
+    let api = ApiClient::init(conf.api_config.as_ref().expect(""), Arc::clone(&token)).await;
+    match command {
+        Command::Serve => unreachable!(),
+        Command::Login => {
+            let access_token = prompt("access token(留空时用refresh token获取)")?;
+            let refresh_token = prompt("refresh token")?;
+            {
+                let mut t = token.lock().await;
+                t.set_api_token(&access_token, &refresh_token)?;
+                if access_token.is_empty() {
+                    t.refresh_token().await?;
+                }
+            }
+            let me = api.users_me().await?;
+            println!("logged in as {} <{}>", me.full_name, me.email);
+        }
+        Command::Vehicles => {
+            for v in api.vehicles().await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    v.id, v.vehicle_id, v.vin, v.display_name, v.state
+                );
+            }
+        }
+        Command::Export { vehicle, output } => {
+            let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+            let e = export(&mut pika, vehicle).await?;
+            let json = serde_json::to_string_pretty(&e).unwrap();
+            match output {
+                Some(path) => std::fs::write(path, json)?,
+                None => println!("{json}"),
+            }
+        }
+        Command::Import { path, vehicle } => {
+            let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+            let e: Export = serde_json::from_reader(file)?;
+            let vid = vehicle.unwrap_or(e.vehicle_id);
+            let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+            import(&mut pika, vid, &e).await?;
+            println!(
+                "imported vehicle={vid} trips={} charges={} events={}",
+                e.trips.len(),
+                e.charges.len(),
+                e.events.len()
+            );
+        }
+        Command::ImportTeslamate { path, vehicle_id } => {
+            teslamate::import(&path, vehicle_id, conf).await?;
+        }
+        Command::Replay { path, speed, pika } => {
+            let stats = replay::replay(&path, speed, &pika, conf).await?;
+            for (vehicle_id, s) in stats.iter() {
+                println!(
+                    "vehicle {vehicle_id}: updates={} snapshots={} trips={} charges={}",
+                    s.updates,
+                    s.snapshots,
+                    s.trips.len(),
+                    s.charges.len()
+                );
+                for t in s.trips.iter() {
+                    let (first, last) = (&t.track[0], &t.track[t.track.len() - 1]);
+                    println!(
+                        "  trip {} -> {} points={}",
+                        first.timestamp,
+                        last.timestamp,
+                        t.track.len()
+                    );
+                }
+                for c in s.charges.iter() {
+                    println!(
+                        "  charge {} -> {} {}% -> {}% {:.1}kWh",
+                        c.start_timestamp,
+                        c.end_timestamp,
+                        c.start_battery_level,
+                        c.end_battery_level,
+                        c.charge_energy_added
+                    );
+                }
+            }
+        }
+        Command::Reprocess { vehicle, from, to } => {
+            let geocoder = geocoder::Geocoder::load(
+                &conf.geocoder.clone().unwrap_or_default(),
+                &conf.api_config.clone().unwrap_or_default(),
+            );
+            let summary = reprocess::reprocess(conf, &geocoder, vehicle, from, to).await?;
+            println!("{:?}", summary);
+        }
+        Command::Db { command } => {
+            let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+            match command {
+                DbCommand::Verify { vehicle } => {
+                    let mut bad_total = 0;
+                    for (table, count, bad) in pika.verify(vehicle).await? {
+                        println!("{table}\t{count}\t{bad}");
+                        bad_total += bad;
+                    }
+                    if bad_total > 0 {
+                        return Err(Error::VerifyErr(format!(
+                            "{bad_total} entries failed to decode"
+                        )));
+                    }
+                }
+                DbCommand::Compact => {
+                    pika.compact().await?;
+                    println!("compact started");
+                }
+            }
+        }
+        Command::Token {
+            command: TokenCommand::Refresh,
+        } => {
+            let mut t = token.lock().await;
+            t.refresh_token().await?;
+            println!("token expires in {}s", t.expires_in().unwrap_or_default());
+        }
+        Command::Status => {
+            match token.lock().await.expires_in() {
+                Some(expires_in) => println!("token: expires in {expires_in}s"),
+                None => println!("token: not set"),
+            }
+            match PikaConnection::shared(&conf.pika_address).await {
+                Ok(mut pika) => match pika.ping().await {
+                    Ok(()) => println!("pika: ok"),
+                    Err(e) => println!("pika: {e}"),
+                },
+                Err(e) => println!("pika: {e}"),
+            }
+            match api.vehicles().await {
+                Ok(vehicles) => {
+                    for v in vehicles {
+                        println!("vehicle {} {}: {}", v.vehicle_id, v.display_name, v.state);
+                    }
+                }
+                Err(e) => println!("vehicles: {e}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_subcommands() {
+        use clap::Parser;
+        #[derive(Parser)]
+        struct Opts {
+            #[clap(subcommand)]
+            command: Command,
+        }
+        let opts = Opts::parse_from(["app", "db", "verify", "--vehicle", "1"]);
+        assert!(matches!(
+            opts.command,
+            Command::Db {
+                command: DbCommand::Verify { vehicle: 1 }
+            }
+        ));
+        let opts = Opts::parse_from([
+            "app",
+            "reprocess",
+            "--vehicle",
+            "1",
+            "--from",
+            "20240101",
+            "--to",
+            "20240102",
+        ]);
+        assert!(matches!(
+            opts.command,
+            Command::Reprocess { from: 20240101, .. }
+        ));
+    }
+}
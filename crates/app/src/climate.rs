@@ -0,0 +1,193 @@
+//! 空调使用记录(预热/预冷, 宠物/露营模式, 除霜)和车内外温度
+use base::pb::tesla::*;
+use base::timestamp_ms;
+use serde::Serialize;
+
+/// 只有SOC变化可用时, 按该容量(kWh)估算耗电
+pub const DEFAULT_CAPACITY: f64 = 75.0;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ClimateSession {
+    /// preconditioning/dog/camp/keep/defrost
+    pub mode: String,
+    pub start_timestamp: i64,
+    pub end_timestamp: i64,
+    /// 秒
+    pub duration: i64,
+    /// kWh, 优先按stream推送的功率积分, 否则按SOC下降估算
+    pub energy: f64,
+    pub start_inside_temp: f64,
+    pub end_inside_temp: f64,
+    pub outside_temp: f64,
+    pub driver_temp_setting: f64,
+    #[serde(skip)]
+    start_battery_level: f64,
+    #[serde(skip)]
+    end_battery_level: f64,
+    #[serde(skip)]
+    temps: Vec<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Temperature {
+    pub timestamp: i64,
+    pub inside_temp: f64,
+    pub outside_temp: f64,
+    pub driver_temp_setting: f64,
+    pub is_climate_on: bool,
+}
+
+/// 停车时空调的使用模式, 行驶中或空调关闭时为None
+fn mode(cs: &VehicleClimateState) -> Option<&str> {
+    match cs.climate_keeper_mode.as_str() {
+        "dog" | "camp" => return Some(&cs.climate_keeper_mode),
+        "on" => return Some("keep"),
+        _ => (),
+    }
+    if cs.defrost_mode != 0 || cs.is_front_defroster_on {
+        return Some("defrost");
+    }
+    (cs.is_preconditioning || cs.is_climate_on).then_some("preconditioning")
+}
+
+/// 区间内stream推送的功率积分(kWh), 没有推送时返回None
+fn stream_energy(pr: &VehiclePeriodRecord) -> Option<f64> {
+    if pr.updates.len() < 2 {
+        return None;
+    }
+    Some(
+        pr.updates
+            .windows(2)
+            .map(|w| {
+                w[0].power.max(0.0)
+                    * (timestamp_ms(w[1].timestamp) - timestamp_ms(w[0].timestamp)) as f64
+            })
+            .sum::<f64>()
+            / 3_600_000.0,
+    )
+}
+
+fn finish(mut s: ClimateSession, capacity: f64) -> ClimateSession {
+    s.duration = (s.end_timestamp - s.start_timestamp) / 1000;
+    if s.energy == 0.0 && s.start_battery_level > s.end_battery_level {
+        s.energy = (s.start_battery_level - s.end_battery_level) / 100.0 * capacity;
+    }
+    if !s.temps.is_empty() {
+        s.outside_temp = s.temps.iter().sum::<f64>() / s.temps.len() as f64;
+    }
+    s
+}
+
+/// records需按时间排序, capacity为电池容量(kWh)
+pub fn detect_sessions(records: &[VehiclePeriodRecord], capacity: f64) -> Vec<ClimateSession> {
+    let mut sessions = vec![];
+    let mut current: Option<ClimateSession> = None;
+    for pr in records.iter() {
+        let snapshot = match pr.snapshot.as_ref() {
+            Some(s) => s,
+            None => continue,
+        };
+        let cs = match snapshot.climate_state.as_ref() {
+            Some(cs) => cs,
+            None => continue,
+        };
+        let driving = pr.updates.iter().any(|ds| ds.speed > 0.0);
+        let mode = mode(cs).filter(|_| !driving);
+        if current.as_ref().map(|s| s.mode.as_str()) != mode {
+            if let Some(s) = current.take() {
+                sessions.push(finish(s, capacity));
+            }
+        }
+        let mode = match mode {
+            Some(mode) => mode,
+            None => continue,
+        };
+        let battery_level = snapshot
+            .charge_state
+            .as_ref()
+            .map(|c| c.usable_battery_level)
+            .unwrap_or_default();
+        let s = current.get_or_insert_with(|| ClimateSession {
+            mode: mode.to_string(),
+            start_timestamp: pr.timestamp * 1000,
+            start_inside_temp: cs.inside_temp,
+            start_battery_level: battery_level,
+            ..Default::default()
+        });
+        s.end_timestamp = pr.timestamp * 1000;
+        s.end_inside_temp = cs.inside_temp;
+        s.end_battery_level = battery_level;
+        s.driver_temp_setting = cs.driver_temp_setting;
+        s.energy += stream_energy(pr).unwrap_or_default();
+        s.temps.push(cs.outside_temp);
+    }
+    if let Some(s) = current {
+        sessions.push(finish(s, capacity));
+    }
+    sessions
+}
+
+pub fn temperatures(records: &[VehiclePeriodRecord]) -> Vec<Temperature> {
+    records
+        .iter()
+        .filter_map(|pr| {
+            let cs = pr.snapshot.as_ref()?.climate_state.as_ref()?;
+            Some(Temperature {
+                timestamp: pr.timestamp * 1000,
+                inside_temp: cs.inside_temp,
+                outside_temp: cs.outside_temp,
+                driver_temp_setting: cs.driver_temp_setting,
+                is_climate_on: cs.is_climate_on,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(minute: i64, climate: VehicleClimateState, level: f64) -> VehiclePeriodRecord {
+        VehiclePeriodRecord {
+            timestamp: 1700000000 + minute * 60,
+            snapshot: Some(VehicleData {
+                climate_state: Some(climate),
+                charge_state: Some(VehicleChargeState {
+                    usable_battery_level: level,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detect_preconditioning_and_dog_mode() {
+        let on = VehicleClimateState {
+            is_climate_on: true,
+            inside_temp: 5.0,
+            ..Default::default()
+        };
+        let dog = VehicleClimateState {
+            is_climate_on: true,
+            climate_keeper_mode: "dog".into(),
+            ..Default::default()
+        };
+        let records = vec![
+            record(0, Default::default(), 80.0),
+            record(1, on.clone(), 80.0),
+            record(11, on, 79.0),
+            record(12, dog.clone(), 79.0),
+            record(42, dog, 77.0),
+            record(43, Default::default(), 77.0),
+        ];
+        let sessions = detect_sessions(&records, 75.0);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].mode, "preconditioning");
+        assert_eq!(sessions[0].duration, 600);
+        assert!((sessions[0].energy - 0.75).abs() < 1e-9);
+        assert_eq!(sessions[1].mode, "dog");
+        assert!((sessions[1].energy - 1.5).abs() < 1e-9);
+    }
+}
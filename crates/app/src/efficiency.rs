@@ -0,0 +1,203 @@
+//! 能耗分析(Wh/km), 基于stream推送的power和odometer
+use base::pb::tesla::*;
+use base::timestamp_ms;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const KM_PER_MILE: f64 = 1.609344;
+/// 相邻两次推送间隔超过该值(ms)时不计入
+const MAX_GAP: i64 = 60 * 1000;
+/// 停车超过该时长(ms)视为新的行程
+const TRIP_GAP: i64 = 10 * 60 * 1000;
+const SPEED_BAND: f64 = 20.0;
+const TEMPERATURE_BAND: f64 = 5.0;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Consumption {
+    pub distance: f64,
+    pub energy: f64,
+    pub wh_per_km: f64,
+}
+
+impl Consumption {
+    fn add(&mut self, distance: f64, energy: f64) {
+        self.distance += distance;
+        self.energy += energy;
+        self.wh_per_km = if self.distance > 0.0 {
+            self.energy * 1000.0 / self.distance
+        } else {
+            0.0
+        };
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TripConsumption {
+    pub start_timestamp: i64,
+    pub end_timestamp: i64,
+    pub outside_temp: f64,
+    #[serde(flatten)]
+    pub consumption: Consumption,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Bucket {
+    /// 区间下限, km/h或℃
+    pub from: f64,
+    #[serde(flatten)]
+    pub consumption: Consumption,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Efficiency {
+    #[serde(flatten)]
+    pub total: Consumption,
+    pub trips: Vec<TripConsumption>,
+    pub by_speed: Vec<Bucket>,
+    pub by_temperature: Vec<Bucket>,
+}
+
+fn buckets(m: BTreeMap<i64, Consumption>, band: f64) -> Vec<Bucket> {
+    m.into_iter()
+        .map(|(k, consumption)| Bucket {
+            from: k as f64 * band,
+            consumption,
+        })
+        .collect()
+}
+
+/// records需按时间排序
+pub fn analyse(records: &[VehiclePeriodRecord]) -> Efficiency {
+    let mut e = Efficiency::default();
+    let mut by_speed: BTreeMap<i64, Consumption> = BTreeMap::new();
+    let mut by_temperature: BTreeMap<i64, Consumption> = BTreeMap::new();
+    let mut trip: Option<TripConsumption> = None;
+    let mut temps = vec![];
+    let mut last: Option<&DrivingState> = None;
+    for pr in records.iter() {
+        let outside_temp = pr
+            .snapshot
+            .as_ref()
+            .and_then(|s| s.climate_state.as_ref())
+            .map(|c| c.outside_temp);
+        for ds in pr.updates.iter() {
+            let prev = match last.replace(ds) {
+                Some(prev) => prev,
+                None => continue,
+            };
+            let (t0, t1) = (timestamp_ms(prev.timestamp), timestamp_ms(ds.timestamp));
+            // 停车过久则结束当前行程
+            if t1 - t0 > TRIP_GAP {
+                if let Some(t) = trip.take() {
+                    e.trips.push(finish_trip(t, &mut temps));
+                }
+            }
+            if t1 - t0 > MAX_GAP || t1 <= t0 {
+                continue;
+            }
+            let distance = (ds.odometer - prev.odometer) * KM_PER_MILE;
+            let energy = prev.power * (t1 - t0) as f64 / 3_600_000.0;
+            let moving = distance > 0.0;
+            // 行程中停车(堵车/开空调)的消耗计入行程, 不计入速度区间; 充电时power为负, 不计入
+            if !moving && (trip.is_none() || energy <= 0.0) {
+                continue;
+            }
+            e.total.add(distance, energy);
+            if moving {
+                let speed = distance / ((t1 - t0) as f64 / 3_600_000.0);
+                by_speed
+                    .entry((speed / SPEED_BAND).floor() as i64)
+                    .or_default()
+                    .add(distance, energy);
+            }
+            if let Some(temp) = outside_temp {
+                by_temperature
+                    .entry((temp / TEMPERATURE_BAND).floor() as i64)
+                    .or_default()
+                    .add(distance, energy);
+                temps.push(temp);
+            }
+            let t = trip.get_or_insert_with(|| TripConsumption {
+                start_timestamp: t0,
+                ..Default::default()
+            });
+            t.end_timestamp = t1;
+            t.consumption.add(distance, energy);
+        }
+    }
+    if let Some(t) = trip.take() {
+        e.trips.push(finish_trip(t, &mut temps));
+    }
+    e.by_speed = buckets(by_speed, SPEED_BAND);
+    e.by_temperature = buckets(by_temperature, TEMPERATURE_BAND);
+    e
+}
+
+fn finish_trip(mut t: TripConsumption, temps: &mut Vec<f64>) -> TripConsumption {
+    if !temps.is_empty() {
+        t.outside_temp = temps.iter().sum::<f64>() / temps.len() as f64;
+    }
+    temps.clear();
+    t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumption_per_km() {
+        let base = 1700000000000;
+        // 36km/h, 15kW 持续3分钟: 1.8km, 0.75kWh
+        let updates = (0..=6)
+            .map(|i| DrivingState {
+                timestamp: base + i * 30_000,
+                odometer: 100.0 + i as f64 * 0.3 / KM_PER_MILE,
+                power: 15.0,
+                ..Default::default()
+            })
+            .collect();
+        let records = vec![VehiclePeriodRecord {
+            updates,
+            snapshot: Some(VehicleData {
+                climate_state: Some(VehicleClimateState {
+                    outside_temp: 12.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }];
+        let e = analyse(&records);
+        assert!((e.total.distance - 1.8).abs() < 1e-6);
+        assert!((e.total.wh_per_km - 750.0 / 1.8).abs() < 1e-6);
+        assert_eq!(e.trips.len(), 1);
+        assert_eq!(e.by_speed.len(), 1);
+        assert_eq!(e.by_speed[0].from, 20.0);
+        assert_eq!(e.by_temperature[0].from, 10.0);
+    }
+
+    #[test]
+    fn stationary_energy_in_trip() {
+        let base = 1700000000000;
+        // 前两段各行驶0.3km, 后两段停车, 每段30秒15kW即0.125kWh
+        let updates = (0..=4)
+            .map(|i| DrivingState {
+                timestamp: base + i * 30_000,
+                odometer: 100.0 + i.min(2) as f64 * 0.3 / KM_PER_MILE,
+                power: 15.0,
+                ..Default::default()
+            })
+            .collect();
+        let records = vec![VehiclePeriodRecord {
+            updates,
+            ..Default::default()
+        }];
+        let e = analyse(&records);
+        assert!((e.total.distance - 0.6).abs() < 1e-6);
+        assert!((e.total.energy - 0.5).abs() < 1e-6);
+        assert!((e.trips[0].consumption.energy - 0.5).abs() < 1e-6);
+        let by_speed: f64 = e.by_speed.iter().map(|b| b.consumption.energy).sum();
+        assert!((by_speed - 0.25).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,90 @@
+//! 比较相邻快照的车门/车窗/锁车/哨兵等状态, 生成事件
+use base::pb::tesla::*;
+
+type Field = (
+    &'static str,
+    fn(&VehicleState) -> bool,
+    &'static str,
+    &'static str,
+);
+
+/// (名称, 取值, true时的事件, false时的事件)
+const FIELDS: &[Field] = &[
+    ("driver_door", |vs| vs.df != 0, "opened", "closed"),
+    ("passenger_door", |vs| vs.pf != 0, "opened", "closed"),
+    ("rear_driver_door", |vs| vs.dr != 0, "opened", "closed"),
+    ("rear_passenger_door", |vs| vs.pr != 0, "opened", "closed"),
+    ("frunk", |vs| vs.ft != 0, "opened", "closed"),
+    ("trunk", |vs| vs.rt != 0, "opened", "closed"),
+    ("driver_window", |vs| vs.fd_window != 0, "opened", "closed"),
+    (
+        "passenger_window",
+        |vs| vs.fp_window != 0,
+        "opened",
+        "closed",
+    ),
+    (
+        "rear_driver_window",
+        |vs| vs.rd_window != 0,
+        "opened",
+        "closed",
+    ),
+    (
+        "rear_passenger_window",
+        |vs| vs.rp_window != 0,
+        "opened",
+        "closed",
+    ),
+    ("locked", |vs| vs.locked, "locked", "unlocked"),
+    ("sentry_mode", |vs| vs.sentry_mode, "on", "off"),
+    ("user_present", |vs| vs.is_user_present, "on", "off"),
+    ("valet_mode", |vs| vs.valet_mode, "on", "off"),
+];
+
+#[derive(Default)]
+pub struct EventExtractor {
+    last: Option<VehicleState>,
+}
+
+impl EventExtractor {
+    /// 第一个快照只作为比较基准
+    pub fn update(&mut self, timestamp: i64, vs: &VehicleState) -> Vec<VehicleEvent> {
+        let last = match self.last.replace(vs.clone()) {
+            Some(last) => last,
+            None => return vec![],
+        };
+        FIELDS
+            .iter()
+            .filter(|(_, get, _, _)| get(&last) != get(vs))
+            .map(|(name, get, on, off)| VehicleEvent {
+                timestamp,
+                name: name.to_string(),
+                event: if get(vs) { on } else { off }.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_snapshots() {
+        let mut e = EventExtractor::default();
+        let mut vs = VehicleState {
+            locked: true,
+            ..Default::default()
+        };
+        assert!(e.update(1, &vs).is_empty());
+        vs.locked = false;
+        vs.df = 1;
+        let events = e.update(2, &vs);
+        let events: Vec<_> = events
+            .iter()
+            .map(|e| (e.name.as_str(), e.event.as_str()))
+            .collect();
+        assert_eq!(events, [("driver_door", "opened"), ("locked", "unlocked")]);
+        assert!(e.update(3, &vs).is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+//! 离线逆地理编码, 启动时加载GeoNames或自定义POI, 找不到时可以回退到在线服务
+use crate::Error;
+use base::distance_km;
+use base::pb::base::GeocoderConfig;
+use base::pb::tesla::ApiConfig;
+use futures_util::future::BoxFuture;
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 网格大小(度), 约11km
+const CELL: f64 = 0.1;
+/// 在线服务请求超时, 连接超时和代理同ApiConfig
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 在线查不到的坐标在该时间内不再查询
+const MISS_TTL: Duration = Duration::from_secs(6 * 3600);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Place {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// 可插拔的在线逆地理编码服务
+pub trait OnlineGeocoder: Send + Sync {
+    fn reverse(&self, latitude: f64, longitude: f64) -> BoxFuture<'_, Option<String>>;
+}
+
+/// nominatim兼容的在线服务
+pub struct Nominatim {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Nominatim {
+    pub fn new(url: &str, api: &ApiConfig) -> Result<Self, Error> {
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: tesla_api::http_client(api)?,
+        })
+    }
+}
+
+impl OnlineGeocoder for Nominatim {
+    fn reverse(&self, latitude: f64, longitude: f64) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async move {
+            #[derive(Deserialize)]
+            struct XResponse {
+                display_name: Option<String>,
+            }
+            let url = format!(
+                "{}/reverse?format=jsonv2&lat={latitude}&lon={longitude}",
+                self.url
+            );
+            match self.client.get(url).timeout(ONLINE_TIMEOUT).send().await {
+                Ok(resp) => match resp.json::<XResponse>().await {
+                    Ok(r) => r.display_name,
+                    Err(e) => {
+                        error!("nominatim decode: {e}");
+                        None
+                    }
+                },
+                Err(e) => {
+                    error!("nominatim request: {e}");
+                    None
+                }
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Geocoder {
+    places: Vec<Place>,
+    grid: HashMap<(i32, i32), Vec<usize>>,
+    max_distance_km: f64,
+    pub online: Option<Box<dyn OnlineGeocoder>>,
+    /// 在线查询失败的坐标(约100米精度)和查询时间
+    misses: Mutex<HashMap<(i64, i64), Instant>>,
+}
+
+fn cell(latitude: f64, longitude: f64) -> (i32, i32) {
+    (
+        (latitude / CELL).floor() as i32,
+        (longitude / CELL).floor() as i32,
+    )
+}
+
+impl Geocoder {
+    /// 按配置加载, 加载失败只记录日志
+    pub fn load(conf: &GeocoderConfig, api: &ApiConfig) -> Self {
+        let mut g = Geocoder {
+            max_distance_km: if conf.max_distance_km > 0.0 {
+                conf.max_distance_km
+            } else {
+                5.0
+            },
+            ..Default::default()
+        };
+        if !conf.geonames_path.is_empty() {
+            match load_geonames(&conf.geonames_path) {
+                Ok(places) => g.add_places(places),
+                Err(e) => error!("load geonames {}: {e}", conf.geonames_path),
+            }
+        }
+        if !conf.poi_path.is_empty() {
+            match load_poi(&conf.poi_path) {
+                Ok(places) => g.add_places(places),
+                Err(e) => error!("load poi {}: {e}", conf.poi_path),
+            }
+        }
+        if !conf.online_url.is_empty() {
+            match Nominatim::new(&conf.online_url, api) {
+                Ok(n) => g.online = Some(Box::new(n)),
+                Err(e) => error!("nominatim {}: {e}", conf.online_url),
+            }
+        }
+        info!("geocoder loaded {} places", g.places.len());
+        g
+    }
+
+    pub fn add_places(&mut self, places: Vec<Place>) {
+        for p in places {
+            self.grid
+                .entry(cell(p.latitude, p.longitude))
+                .or_default()
+                .push(self.places.len());
+            self.places.push(p);
+        }
+    }
+
+    /// 离线查找最近的地点
+    pub fn nearest(&self, latitude: f64, longitude: f64) -> Option<&Place> {
+        let (x, y) = cell(latitude, longitude);
+        // 经度方向的网格在高纬度会变窄, 按纬度放大搜索范围
+        let rx = (self.max_distance_km / (CELL * 111.0)).ceil() as i32;
+        let ry = (rx as f64 / latitude.to_radians().cos().max(0.01)).ceil() as i32;
+        (x - rx..=x + rx)
+            .flat_map(|i| (y - ry..=y + ry).map(move |j| (i, j)))
+            .filter_map(|c| self.grid.get(&c))
+            .flatten()
+            .map(|i| {
+                let p = &self.places[*i];
+                (distance_km(latitude, longitude, p.latitude, p.longitude), p)
+            })
+            .filter(|(d, _)| *d <= self.max_distance_km)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, p)| p)
+    }
+
+    /// 先查离线数据, 找不到再查在线服务; 在线查不到的坐标在MISS_TTL内直接返回空
+    pub async fn address(&self, latitude: f64, longitude: f64) -> String {
+        if let Some(p) = self.nearest(latitude, longitude) {
+            return p.name.clone();
+        }
+        let online = match &self.online {
+            Some(online) => online,
+            None => return String::new(),
+        };
+        let key = (
+            (latitude * 1000.0).round() as i64,
+            (longitude * 1000.0).round() as i64,
+        );
+        {
+            let mut misses = self.misses.lock().unwrap();
+            misses.retain(|_, t| t.elapsed() < MISS_TTL);
+            if misses.contains_key(&key) {
+                return String::new();
+            }
+        }
+        match online.reverse(latitude, longitude).await {
+            Some(name) => name,
+            None => {
+                self.misses.lock().unwrap().insert(key, Instant::now());
+                String::new()
+            }
+        }
+    }
+}
+
+/// GeoNames导出格式: geonameid, name, asciiname, alternatenames, latitude, longitude, ...
+pub fn load_geonames(path: &str) -> Result<Vec<Place>, Error> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split('\t').collect();
+            Some(Place {
+                name: cols.get(1)?.to_string(),
+                latitude: cols.get(4)?.parse().ok()?,
+                longitude: cols.get(5)?.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+/// 自定义POI csv: name,latitude,longitude
+pub fn load_poi(path: &str) -> Result<Vec<Place>, Error> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let mut v = vec![];
+    for p in reader.deserialize() {
+        v.push(p?);
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn nearest_place() {
+        let mut g = Geocoder {
+            max_distance_km: 5.0,
+            ..Default::default()
+        };
+        g.add_places(vec![
+            Place {
+                name: "People's Square".into(),
+                latitude: 31.2304,
+                longitude: 121.4737,
+            },
+            Place {
+                name: "Lujiazui".into(),
+                latitude: 31.2397,
+                longitude: 121.4998,
+            },
+        ]);
+        assert_eq!(g.nearest(31.231, 121.475).unwrap().name, "People's Square");
+        assert_eq!(g.nearest(31.24, 121.50).unwrap().name, "Lujiazui");
+        assert!(g.nearest(39.9, 116.4).is_none());
+    }
+
+    /// 总是查不到, 记录查询次数
+    struct Missing(Arc<AtomicUsize>);
+
+    impl OnlineGeocoder for Missing {
+        fn reverse(&self, _: f64, _: f64) -> BoxFuture<'_, Option<String>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { None })
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_online_miss() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let g = Geocoder {
+            online: Some(Box::new(Missing(calls.clone()))),
+            ..Default::default()
+        };
+        assert_eq!(g.address(31.2304, 121.4737).await, "");
+        assert_eq!(g.address(31.2303, 121.4738).await, "");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(g.address(39.9, 116.4).await, "");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
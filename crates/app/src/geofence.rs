@@ -0,0 +1,153 @@
+//! 地理围栏, 配置文件和接口保存的围栏合并使用
+use base::distance_km;
+use base::pb::base::*;
+use db::pika::PikaConnection;
+use std::collections::HashSet;
+
+pub fn contains(g: &Geofence, latitude: f64, longitude: f64) -> bool {
+    if g.radius > 0.0 {
+        return distance_km(g.latitude, g.longitude, latitude, longitude) * 1000.0 <= g.radius;
+    }
+    // 射线法
+    let mut inside = false;
+    let n = g.polygon.len();
+    for i in 0..n {
+        let a = &g.polygon[i];
+        let b = &g.polygon[(i + n - 1) % n];
+        if (a.latitude > latitude) != (b.latitude > latitude)
+            && longitude
+                < (b.longitude - a.longitude) * (latitude - a.latitude) / (b.latitude - a.latitude)
+                    + a.longitude
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// 坐标所在的第一个围栏名称
+pub fn name_of(geofences: &[Geofence], latitude: f64, longitude: f64) -> String {
+    geofences
+        .iter()
+        .find(|g| contains(g, latitude, longitude))
+        .map(|g| g.name.clone())
+        .unwrap_or_default()
+}
+
+/// 配置中的围栏 + 接口保存的围栏, 同名时以接口保存的为准
+pub async fn load_all(
+    conf: &AppConfig,
+    pika: &mut PikaConnection,
+) -> Result<Vec<Geofence>, db::Error> {
+    let mut v = pika.load_geofences().await?;
+    for g in conf.geofences.iter() {
+        if !v.iter().any(|x| x.name == g.name) {
+            v.push(g.clone());
+        }
+    }
+    Ok(v)
+}
+
+/// 根据位置更新产生进出事件
+#[derive(Default)]
+pub struct GeofenceTracker {
+    inside: Option<HashSet<String>>,
+}
+
+impl GeofenceTracker {
+    pub fn update(
+        &mut self,
+        geofences: &[Geofence],
+        timestamp: i64,
+        latitude: f64,
+        longitude: f64,
+    ) -> Vec<GeofenceEvent> {
+        // stream字段为空时坐标为0, 不是真实位置
+        if latitude == 0.0 && longitude == 0.0 {
+            return vec![];
+        }
+        let now: HashSet<String> = geofences
+            .iter()
+            .filter(|g| contains(g, latitude, longitude))
+            .map(|g| g.name.clone())
+            .collect();
+        // 第一个位置只记录当前所在围栏, 不产生事件
+        let before = match self.inside.replace(now.clone()) {
+            Some(before) => before,
+            None => return vec![],
+        };
+        let event = |name: &String, event: &str| GeofenceEvent {
+            timestamp,
+            name: name.clone(),
+            event: event.to_string(),
+            latitude,
+            longitude,
+        };
+        let mut events: Vec<_> = before
+            .difference(&now)
+            .map(|name| event(name, "exit"))
+            .collect();
+        events.extend(now.difference(&before).map(|name| event(name, "enter")));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn home() -> Geofence {
+        Geofence {
+            name: "Home".into(),
+            latitude: 31.2304,
+            longitude: 121.4737,
+            radius: 200.0,
+            ..Default::default()
+        }
+    }
+
+    fn office() -> Geofence {
+        let p = |latitude, longitude| GeoPoint {
+            latitude,
+            longitude,
+        };
+        Geofence {
+            name: "Office".into(),
+            polygon: vec![
+                p(31.0, 121.0),
+                p(31.0, 121.1),
+                p(31.1, 121.1),
+                p(31.1, 121.0),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn contains_circle_and_polygon() {
+        assert!(contains(&home(), 31.2310, 121.4740));
+        assert!(!contains(&home(), 31.2404, 121.4737));
+        assert!(contains(&office(), 31.05, 121.05));
+        assert!(!contains(&office(), 31.15, 121.05));
+    }
+
+    #[test]
+    fn enter_exit() {
+        let fences = vec![home(), office()];
+        let mut t = GeofenceTracker::default();
+        assert!(t.update(&fences, 1, 31.2304, 121.4737).is_empty());
+        let events = t.update(&fences, 2, 31.05, 121.05);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].name.as_str(), events[0].event.as_str()),
+            ("Home", "exit")
+        );
+        assert_eq!(
+            (events[1].name.as_str(), events[1].event.as_str()),
+            ("Office", "enter")
+        );
+        assert!(t.update(&fences, 3, 31.06, 121.05).is_empty());
+        assert!(t.update(&fences, 4, 0.0, 0.0).is_empty());
+        assert!(t.update(&fences, 5, 31.06, 121.05).is_empty());
+    }
+}
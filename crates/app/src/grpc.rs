@@ -0,0 +1,138 @@
+//! gRPC服务, 供其他内部工具获取车辆数据和历史
+use crate::shutdown::{self, ShutdownReceiver};
+use crate::vehicle_monitor;
+use base::pb::base::AppConfig;
+use base::pb::tesla::tesla_service_server::{TeslaService, TeslaServiceServer};
+use base::pb::tesla::*;
+use db::pika::PikaConnection;
+use futures_core::Stream;
+use log::{error, info};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use tesla_api::ApiClient;
+use tokio::sync::broadcast;
+use tonic::{Request, Response, Status};
+
+/// VehicleMonitor收到的stream推送, (vehicle_id, DrivingState)
+pub fn driving_states() -> &'static broadcast::Sender<(i64, DrivingState)> {
+    static SENDER: OnceLock<broadcast::Sender<(i64, DrivingState)>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(1024).0)
+}
+
+/// 没有订阅者时直接丢弃
+pub fn publish_driving_state(vehicle_id: i64, ds: &DrivingState) {
+    let _ = driving_states().send((vehicle_id, ds.clone()));
+}
+
+fn status(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+pub struct TeslaServiceImpl {
+    api: ApiClient,
+    conf: AppConfig,
+}
+
+impl TeslaServiceImpl {
+    async fn pika(&self) -> Result<PikaConnection, Status> {
+        PikaConnection::shared(&self.conf.pika_address)
+            .await
+            .map_err(status)
+    }
+}
+
+#[tonic::async_trait]
+impl TeslaService for TeslaServiceImpl {
+    async fn get_vehicle_data(
+        &self,
+        request: Request<VehicleRequest>,
+    ) -> Result<Response<VehicleData>, Status> {
+        let vehicle_id = request.into_inner().vehicle_id;
+        let vehicles = self.api.vehicles().await.map_err(status)?;
+        let vehicle = vehicles
+            .iter()
+            .find(|v| v.vehicle_id == vehicle_id)
+            .ok_or_else(|| Status::not_found(format!("vehicle_id={vehicle_id}")))?;
+        let d = vehicle_monitor::vehicle_data_or_cached(&self.api, vehicle)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(d))
+    }
+
+    type SubscribeDrivingStateStream =
+        Pin<Box<dyn Stream<Item = Result<DrivingState, Status>> + Send>>;
+
+    async fn subscribe_driving_state(
+        &self,
+        request: Request<VehicleRequest>,
+    ) -> Result<Response<Self::SubscribeDrivingStateStream>, Status> {
+        let vehicle_id = request.into_inner().vehicle_id;
+        let mut rx = driving_states().subscribe();
+        let s = async_stream::stream! {
+            loop {
+                match rx.recv().await {
+                    Ok((vid, ds)) if vid == vehicle_id => yield Ok(ds),
+                    Ok(_) => (),
+                    // 订阅方处理太慢, 丢弃积压的推送
+                    Err(broadcast::error::RecvError::Lagged(_)) => (),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(s)))
+    }
+
+    async fn get_period_records(
+        &self,
+        request: Request<PeriodRecordsRequest>,
+    ) -> Result<Response<PeriodRecordsResponse>, Status> {
+        let req = request.into_inner();
+        let mut records = self
+            .pika()
+            .await?
+            .load_daily_vehicle_period_records(req.vehicle_id, req.day)
+            .await
+            .map_err(status)?;
+        records.sort_by_key(|r| r.timestamp);
+        Ok(Response::new(PeriodRecordsResponse { records }))
+    }
+
+    async fn get_trips(
+        &self,
+        request: Request<VehicleRequest>,
+    ) -> Result<Response<TripsResponse>, Status> {
+        let trips = self
+            .pika()
+            .await?
+            .load_trips(request.into_inner().vehicle_id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(TripsResponse { trips }))
+    }
+
+    async fn get_charges(
+        &self,
+        request: Request<VehicleRequest>,
+    ) -> Result<Response<ChargesResponse>, Status> {
+        let charges = self
+            .pika()
+            .await?
+            .load_charges(request.into_inner().vehicle_id)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(ChargesResponse { charges }))
+    }
+}
+
+pub async fn serve(api: ApiClient, conf: AppConfig, shutdown: ShutdownReceiver) {
+    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], conf.grpc_port as u16));
+    info!("grpc listening on {addr}");
+    let service = TeslaServiceServer::new(TeslaServiceImpl { api, conf });
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, shutdown::wait(shutdown))
+        .await
+    {
+        error!("grpc serve: {e}");
+    }
+}
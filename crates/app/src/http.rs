@@ -4,12 +4,15 @@
 //! cd examples && cargo run -p example-static-file-server
 //! ```
 
+use crate::reload::ConfigReceiver;
+use crate::shutdown::{self, ShutdownReceiver};
+use crate::{climate, efficiency, geofence, supercharger, tariff, tpms};
 use axum::{
     extract::{Json, Request, State},
     http::StatusCode,
     middleware::{self, Next},
     response::Response,
-    routing::{get_service, post},
+    routing::{get, get_service, post},
     Router,
 };
 use base::{pb::base::*, pb::tesla::*};
@@ -30,6 +33,7 @@ pub enum HttpError {
     StdIoError(std::io::Error),
     SerdeJsonErr(serde_json::Error),
     DbErr(db::Error),
+    AppErr(crate::Error),
 }
 impl axum::response::IntoResponse for HttpError {
     fn into_response(self) -> Response {
@@ -39,6 +43,7 @@ impl axum::response::IntoResponse for HttpError {
             StdIoError(e) => format!("std io error {}", e),
             SerdeJsonErr(e) => format!("json error {}", e),
             DbErr(e) => format!("db err:{e}"),
+            AppErr(e) => format!("app err:{e}"),
         };
         (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
     }
@@ -66,23 +71,33 @@ async fn my_middleware(State(_api): State<MyStateType>, request: Request, next:
 #[derive(Clone)]
 struct MyStateType {
     api: Arc<Mutex<ApiClient>>,
-    conf: AppConfig,
+    conf: ConfigReceiver,
+}
+
+impl MyStateType {
+    /// 当前配置, 同一个请求内应只取一次
+    fn conf(&self) -> Arc<AppConfig> {
+        self.conf.borrow().clone()
+    }
 }
 
 // type MyStateType = Arc<Mutex<MyState>>;
 
-pub async fn httpd(api_client: ApiClient, conf: AppConfig) {
+pub async fn httpd(api_client: ApiClient, conf: ConfigReceiver, shutdown: ShutdownReceiver) {
     let state = MyStateType {
         api: Arc::new(Mutex::new(api_client)),
         conf,
     };
+    let conf = state.conf();
     let ports = Ports {
-        http: state.conf.http_port as u16,
-        https: state.conf.https_port as u16,
+        http: conf.http_port as u16,
+        https: conf.https_port as u16,
     };
 
-    let serve_dir =
-        get_service(ServeDir::new("web/build").fallback(ServeFile::new("web/build/index.html")));
+    let static_dir = std::path::Path::new(&conf.static_dir);
+    let serve_dir = get_service(
+        ServeDir::new(static_dir).fallback(ServeFile::new(static_dir.join("index.html"))),
+    );
     let app = Router::new()
         .route("/api/tesla/track", post(track))
         .route("/api/tesla/vehicles", post(vehicles))
@@ -90,8 +105,24 @@ pub async fn httpd(api_client: ApiClient, conf: AppConfig) {
         .route("/api/tesla/user_me", post(user_me))
         .route("/api/tesla/history_trips", post(history_trips))
         .route("/api/tesla/history_charges", post(history_charges))
+        .route("/api/tesla/charge_cost_summary", post(charge_cost_summary))
+        .route("/api/tesla/battery_health", post(battery_health))
+        .route("/api/tesla/efficiency", post(efficiency))
+        .route("/api/tesla/supercharger_bills", post(supercharger_bills))
+        .route("/api/tesla/supercharger_sync", post(supercharger_sync))
+        .route("/api/tesla/geofences", post(geofences))
+        .route("/api/tesla/geofence/save", post(save_geofence))
+        .route("/api/tesla/geofence/delete", post(delete_geofence))
+        .route("/api/tesla/geofence_events", post(geofence_events))
+        .route("/api/tesla/updates", post(software_updates))
+        .route("/api/tesla/tpms", post(tpms_history))
+        .route("/api/tesla/events", post(vehicle_events))
+        .route("/api/tesla/climate_sessions", post(climate_sessions))
+        .route("/api/tesla/temperatures", post(temperatures))
         .layer(middleware::from_fn_with_state(state.clone(), my_middleware))
         .route("/api/set_api_token", post(set_api_token))
+        .route("/metrics", get(metrics))
+        .route("/api/health", get(health))
         .nest_service("/", serve_dir)
         .with_state(state);
 
@@ -102,7 +133,12 @@ pub async fn httpd(api_client: ApiClient, conf: AppConfig) {
         let addr = SocketAddr::from(([0, 0, 0, 0], ports.http));
         info!("listen on {addr}");
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-        axum::serve(listener, app).await.unwrap();
+        // 退出时不再接受新连接, 等待进行中的请求完成
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown::wait(shutdown))
+            .await
+            .unwrap();
+        info!("http server stopped");
     }
 }
 
@@ -156,7 +192,7 @@ async fn track(
     Json(req): Json<VehicleTrackRequest>,
 ) -> Result<Json<RspTrackData>, HttpError> {
     let mut rsp = RspTrackData::default();
-    let mut pika = PikaConnection::connect(&s.conf.pika_address).await?;
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
     let records = pika
         .load_daily_vehicle_period_records(req.id, get_local_date())
         .await?;
@@ -189,43 +225,27 @@ async fn history_trips(
     Json(req): Json<HistoryTripsRequest>,
 ) -> Result<Json<HistoryTripsResponse>, HttpError> {
     let mut rsp = HistoryTripsResponse::default();
-    let mut one_trip = Trip::default();
-    let mut pika = PikaConnection::connect(&s.conf.pika_address).await?;
-    let records = pika
-        .load_daily_vehicle_period_records(req.id, get_local_date())
-        .await?;
-    records
-        .iter()
-        .filter(|r| r.snapshot.is_some())
-        .for_each(|pr| {
-            let snapshot = pr.snapshot.as_ref().unwrap();
-            if snapshot.state == "online" {
-                let mut s = TripSnapshot::default();
-                for ds in pr.updates.iter() {
-                    if ds.timestamp < 1675843854 * 1000 {
-                        s.timestamp = ds.timestamp * 1000;
-                    } else {
-                        s.timestamp = ds.timestamp;
-                    }
-                    let (lat, lng) = wgs_to_bd09(ds.est_lat, ds.est_lng);
-                    s.latitude = lat;
-                    s.longitude = lng;
-                    s.elevation = ds.elevation;
-                    if let Some(cs) = &snapshot.climate_state {
-                        s.inside_temperature = cs.inside_temp;
-                        s.outside_temperature = cs.outside_temp;
-                    }
-                }
-                if s.timestamp > 0 {
-                    one_trip.track.push(s);
-                }
-            } else {
-                if one_trip.track.len() > 0 {
-                    rsp.trips.push(one_trip.clone());
-                    one_trip.track.clear();
-                }
-            }
-        });
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    // 行程和起止地址由车辆监控在行程结束时保存, 这里返回今天开始的
+    let today = Local::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(Local).single())
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_default();
+    rsp.trips = pika
+        .load_trips(req.id)
+        .await?
+        .into_iter()
+        .filter(|t| t.timestamp >= today)
+        .collect();
+    for t in rsp.trips.iter_mut() {
+        for ts in t.track.iter_mut() {
+            let (lat, lng) = wgs_to_bd09(ts.latitude, ts.longitude);
+            ts.latitude = lat;
+            ts.longitude = lng;
+        }
+    }
     Ok(Json(rsp))
 }
 
@@ -245,24 +265,280 @@ async fn history_charges(
     Json(req): Json<HistoryChargesRequest>,
 ) -> Result<Json<HistoryChargesResponse>, HttpError> {
     let mut rsp = HistoryChargesResponse::default();
-    let mut history = HistoryCharge::default();
-    let mut pika = PikaConnection::connect(&s.conf.pika_address).await?;
-    let records = pika
-        .load_daily_vehicle_period_records(req.id, get_local_date())
-        .await?;
-    for pr in records.iter() {
-        if let Some(snapshot) = &pr.snapshot {
-            if let Some(cs) = &snapshot.charge_state {
-                if cs.charger_power > 0.0 {
-                    history.details.push(cs.clone());
-                }
-            }
-        }
-    }
-    // rsp.history_charges = history.details;
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    // 充电过程由车辆监控识别保存, 这里返回今天结束或还在进行的
+    let today = Local::now()
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(Local).single())
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_default();
+    rsp.history_charges = pika
+        .load_charges(req.id)
+        .await?
+        .into_iter()
+        .filter(|c| c.end_timestamp >= today)
+        .collect();
     Ok(Json(rsp))
 }
 
+/// 按车辆id查询
+#[derive(Deserialize)]
+struct VehicleRequest {
+    id: i64,
+}
+
+/// 按车辆id和日期范围查询
+#[derive(Deserialize)]
+struct VehicleRangeRequest {
+    id: i64,
+    /// yyyymmdd, 默认今天
+    from: Option<i32>,
+    to: Option<i32>,
+}
+
+/// 按月汇总充电费用
+async fn charge_cost_summary(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRequest>,
+) -> Result<Json<Vec<tariff::MonthlyCost>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let charges = pika.load_charges(req.id).await?;
+    Ok(Json(tariff::monthly_summary(&charges)))
+}
+
+/// 电池健康度时间序列
+async fn battery_health(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRequest>,
+) -> Result<Json<Vec<BatteryHealth>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    Ok(Json(pika.load_battery_health(req.id).await?))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DailyConsumption {
+    day: i32,
+    #[serde(flatten)]
+    consumption: efficiency::Consumption,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct EfficiencyResponse {
+    #[serde(flatten)]
+    efficiency: efficiency::Efficiency,
+    daily: Vec<DailyConsumption>,
+}
+
+/// 按天加载区间数据, from/to为yyyymmdd, 默认今天
+async fn load_days(
+    pika: &mut PikaConnection,
+    req: &VehicleRangeRequest,
+) -> Result<Vec<(i32, Vec<VehiclePeriodRecord>)>, db::Error> {
+    let today = get_local_date();
+    pika.load_vehicle_period_records_by_day(
+        req.id,
+        req.from.unwrap_or(today),
+        req.to.unwrap_or(today),
+    )
+    .await
+}
+
+/// 能耗分析, 按行程/天/速度区间/车外温度区间
+async fn efficiency(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRangeRequest>,
+) -> Result<Json<EfficiencyResponse>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let mut records = vec![];
+    let mut daily = vec![];
+    for (day, v) in load_days(&mut pika, &req).await? {
+        daily.push(DailyConsumption {
+            day,
+            consumption: efficiency::analyse(&v).total,
+        });
+        records.extend(v);
+    }
+    Ok(Json(EfficiencyResponse {
+        efficiency: efficiency::analyse(&records),
+        daily,
+    }))
+}
+
+/// 已同步的超充账单
+async fn supercharger_bills(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRequest>,
+) -> Result<Json<Vec<SuperchargerBill>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    Ok(Json(pika.load_supercharger_bills(req.id).await?))
+}
+
+#[derive(Deserialize)]
+struct SuperchargerSyncRequest {
+    id: i64,
+    /// 毫秒
+    start: Option<i64>,
+    end: Option<i64>,
+}
+
+/// 按时间段同步超充账单, id为vehicle_id
+async fn supercharger_sync(
+    State(s): State<MyStateType>,
+    Json(req): Json<SuperchargerSyncRequest>,
+) -> Result<Json<usize>, HttpError> {
+    let api = s.api.lock().await.clone();
+    let vehicles = api.vehicles().await?;
+    let vehicle = match vehicles.iter().find(|v| v.vehicle_id == req.id) {
+        Some(v) => v,
+        None => return Ok(Json(0)),
+    };
+    use chrono::TimeZone;
+    let t = |ms: Option<i64>| ms.and_then(|ms| chrono::Utc.timestamp_millis_opt(ms).single());
+    let n = supercharger::sync(&api, vehicle, &s.conf(), t(req.start), t(req.end)).await?;
+    Ok(Json(n))
+}
+
+/// 全部地理围栏
+async fn geofences(State(s): State<MyStateType>) -> Result<Json<Vec<Geofence>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    Ok(Json(geofence::load_all(&s.conf(), &mut pika).await?))
+}
+
+/// 新增或修改地理围栏
+async fn save_geofence(
+    State(s): State<MyStateType>,
+    Json(req): Json<Geofence>,
+) -> Result<(), HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    pika.save_geofence(&req).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct DeleteGeofenceRequest {
+    name: String,
+}
+
+/// 删除接口保存的地理围栏
+async fn delete_geofence(
+    State(s): State<MyStateType>,
+    Json(req): Json<DeleteGeofenceRequest>,
+) -> Result<(), HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    pika.delete_geofence(&req.name).await?;
+    Ok(())
+}
+
+/// 进出地理围栏事件
+async fn geofence_events(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRequest>,
+) -> Result<Json<Vec<GeofenceEvent>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    Ok(Json(pika.load_geofence_events(req.id).await?))
+}
+
+/// 软件升级历史
+async fn software_updates(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRequest>,
+) -> Result<Json<Vec<SoftwareUpdateRecord>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    Ok(Json(pika.load_software_updates(req.id).await?))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TpmsResponse {
+    samples: Vec<TpmsPressure>,
+    daily: Vec<TpmsDaily>,
+    slow_leaks: Vec<tpms::SlowLeak>,
+}
+
+/// 胎压时间序列(按温度换算)和慢漏气检测
+async fn tpms_history(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRangeRequest>,
+) -> Result<Json<TpmsResponse>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let mut samples = vec![];
+    for (_, records) in load_days(&mut pika, &req).await? {
+        samples.extend(
+            records
+                .iter()
+                .filter_map(|pr| tpms::sample(pr.timestamp * 1000, pr.snapshot.as_ref()?)),
+        );
+    }
+    let daily = pika.load_tpms_daily(req.id).await?;
+    Ok(Json(TpmsResponse {
+        samples,
+        slow_leaks: tpms::slow_leaks(&daily),
+        daily,
+    }))
+}
+
+#[derive(Deserialize)]
+struct VehicleEventsRequest {
+    id: i64,
+    /// 从0开始
+    page: Option<isize>,
+    /// 默认50
+    page_size: Option<isize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct VehicleEventsResponse {
+    total: i64,
+    events: Vec<VehicleEvent>,
+}
+
+/// 车门/车窗/锁车/哨兵等状态变化, 按时间倒序分页
+async fn vehicle_events(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleEventsRequest>,
+) -> Result<Json<VehicleEventsResponse>, HttpError> {
+    let page = req.page.unwrap_or(0).max(0);
+    let page_size = req.page_size.unwrap_or(50).clamp(1, 500);
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let (total, events) = pika.load_vehicle_events(req.id, page, page_size).await?;
+    Ok(Json(VehicleEventsResponse { total, events }))
+}
+
+/// 停车时的空调使用记录, 耗电按最近一次电池容量估算
+async fn climate_sessions(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRangeRequest>,
+) -> Result<Json<Vec<climate::ClimateSession>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let capacity = pika
+        .load_battery_health(req.id)
+        .await?
+        .iter()
+        .rev()
+        .map(|h| h.capacity)
+        .find(|c| *c > 0.0)
+        .unwrap_or(climate::DEFAULT_CAPACITY);
+    let records: Vec<_> = load_days(&mut pika, &req)
+        .await?
+        .into_iter()
+        .flat_map(|(_, v)| v)
+        .collect();
+    Ok(Json(climate::detect_sessions(&records, capacity)))
+}
+
+/// 车内外温度序列
+async fn temperatures(
+    State(s): State<MyStateType>,
+    Json(req): Json<VehicleRangeRequest>,
+) -> Result<Json<Vec<climate::Temperature>>, HttpError> {
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
+    let mut v = vec![];
+    for (_, records) in load_days(&mut pika, &req).await? {
+        v.extend(climate::temperatures(&records));
+    }
+    Ok(Json(v))
+}
+
 /// response for track
 #[derive(Debug, Default, serde::Serialize, Deserialize)]
 struct ReqSnapshots {
@@ -286,7 +562,7 @@ async fn snapshots(
     Json(req): Json<ReqSnapshots>,
 ) -> Result<Json<RspSnapshots>, HttpError> {
     let mut rsp = RspSnapshots::default();
-    let mut pika = PikaConnection::connect(&s.conf.pika_address).await?;
+    let mut pika = PikaConnection::shared(&s.conf().pika_address).await?;
     let records = pika
         .load_daily_vehicle_period_records(req.vehicle_id, get_local_date())
         .await?;
@@ -300,6 +576,29 @@ async fn snapshots(
     Ok(Json(rsp))
 }
 
+/// prometheus指标
+async fn metrics() -> String {
+    crate::metrics::metrics().encode()
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct HealthResponse {
+    ok: bool,
+    vehicles: Vec<crate::supervisor::VehicleHealth>,
+}
+
+/// 各车辆监控的运行状态, 有监控在等待重启时返回503
+pub(crate) async fn health() -> (StatusCode, Json<HealthResponse>) {
+    let vehicles = crate::supervisor::health();
+    let ok = vehicles.iter().all(|v| v.running);
+    let code = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(HealthResponse { ok, vehicles }))
+}
+
 /// get vehicles
 async fn vehicles(State(s): State<MyStateType>) -> Result<Json<Vec<Vehicle>>, HttpError> {
     let v = s.api.lock().await.vehicles().await?;
@@ -322,17 +621,14 @@ async fn vehicle_data(
     State(s): State<MyStateType>,
     Json(req): Json<VehicleDataRequest>,
 ) -> Result<Json<VehicleData>, HttpError> {
-    let mut vd = match s.api.lock().await.vehicle_data(req.id).await {
-        Ok(vd) => vd,
-        Err(_e) => {
-            let path = format!(".cache/{}/vehicle_data.json", req.id);
-            let file = std::fs::File::open(path)?;
-            let reader = std::io::BufReader::new(file);
-            let mut vd: VehicleData = serde_json::from_reader(reader)?;
-            vd.state = "asleep".to_string();
-            vd
-        }
-    };
+    let api = s.api.lock().await;
+    let vehicle = api
+        .vehicles()
+        .await?
+        .into_iter()
+        .find(|v| v.id == req.id)
+        .ok_or_else(|| crate::Error::ArgErr(format!("id={}", req.id)))?;
+    let vd = crate::vehicle_monitor::vehicle_data_or_cached(&api, &vehicle).await?;
     // if let Some(ds) = vd.drive_state.as_mut() {
     //     let (latitude, longitude) = wgs_to_bd09(ds.latitude, ds.longitude);
     //     ds.latitude = latitude;
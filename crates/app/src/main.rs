@@ -1,52 +1,149 @@
 use clap::Parser;
 use log::{error, info};
 use tesla_api::{ApiClient, TokenState};
+mod battery;
+mod charging;
+mod cli;
+mod climate;
+mod efficiency;
+mod events;
+mod geocoder;
+mod geofence;
+mod grpc;
 mod http;
+mod metrics;
+mod mqtt;
+mod notify;
+mod reload;
+mod replay;
+mod reprocess;
+mod shutdown;
+mod software_update;
+mod supercharger;
+mod supervisor;
+mod tariff;
 use base::pb::base::*;
 use base::*;
+use cli::Command;
 use http::*;
+mod teslamate;
+mod tpms;
+mod trip;
 mod vehicle_monitor;
 use std::collections::HashMap;
-use vehicle_monitor::*;
 
 #[derive(Debug, derive_more::Display, derive_more::From)]
 pub enum Error {
     IoErr(std::io::Error),
     DbErr(db::Error),
+    CsvErr(csv::Error),
+    ApiErr(tesla_api::Error),
+    HttpErr(reqwest::Error),
+    SerdeJsonErr(serde_json::Error),
+    #[from(ignore)]
+    NotifyErr(String),
+    #[from(ignore)]
+    ArgErr(String),
+    #[from(ignore)]
+    VerifyErr(String),
 }
 
 #[derive(Parser)]
 #[clap()]
 struct Opts {
+    /// 配置文件(json/toml/yaml), 不指定时只使用默认值和TESLA_*环境变量
     #[clap(short, long)]
-    config: String,
+    config: Option<String>,
+    /// 录制API响应和stream推送到该文件, 可用replay回放
+    #[clap(long)]
+    record: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
 #[tokio::main]
 async fn main() {
     init_logger();
     let opts: Opts = Opts::parse();
-    check_make_dir(".cache");
+    let conf = match AppConfig::load(opts.config.as_deref()) {
+        Ok(conf) => conf,
+        Err(e) => {
+            error!("load config: {e}");
+            std::process::exit(1);
+        }
+    };
+    base::set_data_dir(&conf.data_dir);
+    check_make_dir(&conf.data_dir);
     let cookie = r#"gdp_user_id=gioenc-c5d09234,8ccd,5bd9,a37d,5e54ceaed440;"#;
-    let conf = AppConfig::load(&opts.config).expect("");
     info!("start conf={:?}", conf);
     let token = TokenState::new(conf.api_config.as_ref().expect(""), cookie.into())
         .await
         .unwrap();
     let token = std::sync::Arc::new(tokio::sync::Mutex::new(token));
-    {
+    match opts.command.unwrap_or(Command::Serve) {
+        Command::Serve => (),
+        command => {
+            if let Err(e) = cli::run(command, &conf, token).await {
+                error!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+    let recorder = opts.record.as_ref().map(|path| {
+        std::sync::Arc::new(tesla_api::record::Recorder::create(path).expect("create record file"))
+    });
+    // 车辆监控在行程结束时解析起止地址
+    let geocoder = std::sync::Arc::new(geocoder::Geocoder::load(
+        &conf.geocoder.clone().unwrap_or_default(),
+        &conf.api_config.clone().unwrap_or_default(),
+    ));
+    // 配置文件变化或SIGHUP时重新加载
+    let (conf_tx, conf_rx) = tokio::sync::watch::channel(std::sync::Arc::new(conf.clone()));
+    reload::spawn(opts.config.clone(), conf_tx);
+    // SIGTERM/SIGINT时有序退出
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        shutdown_tx.send_replace(true);
+    });
+    let http = {
         // HTTP 服务
-        let conf = conf.clone();
         let client = ApiClient::init(
             conf.api_config.as_ref().expect(""),
             std::sync::Arc::clone(&token),
         )
         .await;
+        let conf = conf_rx.clone();
+        let shutdown = shutdown_rx.clone();
         tokio::spawn(async move {
-            httpd(client, conf).await;
-        });
-    }
-    let mut monitors: HashMap<i64, VehicleMonitor> = HashMap::new();
+            httpd(client, conf, shutdown).await;
+        })
+    };
+    let grpc = if conf.grpc_port > 0 {
+        // gRPC 服务
+        let conf = conf.clone();
+        let client = ApiClient::init(
+            conf.api_config.as_ref().expect(""),
+            std::sync::Arc::clone(&token),
+        )
+        .await;
+        Some(tokio::spawn(grpc::serve(client, conf, shutdown_rx.clone())))
+    } else {
+        None
+    };
+    let mqtt = mqtt::MqttPublisher::start(&conf.mqtt.clone().unwrap_or_default());
+    let mut monitors: HashMap<i64, supervisor::Supervised> = HashMap::new();
+    // 超充账单每6小时同步一次
+    supercharger::spawn_sync(
+        ApiClient::init(
+            conf.api_config.as_ref().expect(""),
+            std::sync::Arc::clone(&token),
+        )
+        .await,
+        conf_rx.clone(),
+        shutdown_rx.clone(),
+    );
     {
         // 检测vehicles()并启动监控 & check refresh access token
         let conf = conf.clone();
@@ -54,56 +151,90 @@ async fn main() {
             conf.api_config.as_ref().expect(""),
             std::sync::Arc::clone(&token),
         )
-        .await;
+        .await
+        .with_recorder(recorder.clone());
         loop {
-            {
-                match token.lock().await.check_refresh_token().await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Maybe it's someting wrong with your token, {e}");
-                        tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-                        continue;
+            let conf = conf_rx.borrow().clone();
+            // vehicles()重试时可能要等几分钟, 收到退出信号时直接结束本轮检查
+            let wait = async {
+                {
+                    let refreshed = {
+                        let mut t = token.lock().await;
+                        let refreshed = t.check_refresh_token().await;
+                        if let Some(expires_in) = t.expires_in() {
+                            metrics::metrics().token_expires_in.set(expires_in);
+                        }
+                        refreshed
+                    };
+                    match refreshed {
+                        Ok(()) => (),
+                        Err(e) => {
+                            error!("Maybe it's someting wrong with your token, {e}");
+                            return tokio::time::Duration::from_secs(30);
+                        }
                     }
                 }
-            }
-            let vehicles = client.vehicles().await;
-            match vehicles {
-                Ok(vehicles) => {
-                    for v in vehicles.iter() {
-                        if monitors.contains_key(&v.id) {
-                            continue;
-                        }
-                        let token = std::sync::Arc::clone(&token);
-                        let api = ApiClient::init(conf.api_config.as_ref().expect(""), token).await;
-                        let vm = VehicleMonitor::init(api, v.clone(), conf.clone()).await;
-                        match vm {
-                            Ok(vm) => {
-                                monitors.insert(v.id, vm);
+                let vehicles = client.vehicles().await;
+                match vehicles {
+                    Ok(vehicles) => {
+                        for v in vehicles.iter() {
+                            if monitors.contains_key(&v.id) {
+                                continue;
                             }
-                            Err(e) => {
-                                error!("VehicleMonitor::init {}", e);
+                            let token = std::sync::Arc::clone(&token);
+                            let api = ApiClient::init(conf.api_config.as_ref().expect(""), token)
+                                .await
+                                .with_recorder(recorder.clone());
+                            let vm = supervisor::supervise(
+                                api,
+                                v.clone(),
+                                conf_rx.clone(),
+                                mqtt.clone(),
+                                std::sync::Arc::clone(&geocoder),
+                            );
+                            monitors.insert(v.id, vm);
+                        }
+                        let delete_list = monitors
+                            .iter()
+                            .filter(|(k, _v)| vehicles.iter().find(|v| **k == v.id).is_none())
+                            .map(|(k, _v)| k.clone())
+                            .collect::<Vec<_>>();
+                        for k in delete_list.iter() {
+                            if let Some(vm) = monitors.remove(k) {
+                                tokio::spawn(vm.stop());
                             }
                         }
                     }
-                    let delete_list = monitors
-                        .iter()
-                        .filter(|(k, _v)| vehicles.iter().find(|v| **k == v.id).is_none())
-                        .map(|(k, _v)| k.clone())
-                        .collect::<Vec<_>>();
-                    for k in delete_list.iter() {
-                        if let Some(vm) = monitors.remove(k) {
-                            vm.exit_sender.send("exit".into()).unwrap();
+                    Err(e) => match e {
+                        tesla_api::Error::Unauthorized => {
+                            info!("api.vehicles err {e}");
                         }
-                    }
+                        _ => error!("api.vehicles: {}", e),
+                    },
                 }
-                Err(e) => match e {
-                    tesla_api::Error::Unauthorized => {
-                        info!("api.vehicles err {e}");
-                    }
-                    _ => error!("api.vehicles: {}", e),
-                },
+                tokio::time::Duration::from_secs(60)
+            };
+            let wait = tokio::select! {
+                _ = shutdown::wait(shutdown_rx.clone()) => break,
+                wait = wait => wait,
+            };
+            tokio::select! {
+                _ = shutdown::wait(shutdown_rx.clone()) => break,
+                _ = tokio::time::sleep(wait) => (),
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
         }
     }
+    // 各车辆监控关闭stream并保存区间数据, docker stop默认10秒后强制结束
+    let stops = futures_util::future::join_all(monitors.into_values().map(|vm| vm.stop()));
+    if tokio::time::timeout(std::time::Duration::from_secs(8), stops)
+        .await
+        .is_err()
+    {
+        error!("vehicle monitors did not stop in time");
+    }
+    let _ = http.await;
+    if let Some(grpc) = grpc {
+        let _ = grpc.await;
+    }
+    info!("shutdown complete");
 }
@@ -0,0 +1,181 @@
+//! Prometheus指标, 通过 /metrics 导出
+use base::pb::tesla::*;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+
+pub struct Metrics {
+    registry: Registry,
+    pub battery_level: GaugeVec,
+    pub battery_range: GaugeVec,
+    pub odometer: GaugeVec,
+    pub charger_power: GaugeVec,
+    pub inside_temp: GaugeVec,
+    pub outside_temp: GaugeVec,
+    pub tpms_pressure: GaugeVec,
+    pub locked: GaugeVec,
+    pub sentry_mode: GaugeVec,
+    pub driving_state: GaugeVec,
+    pub stream_reconnects: IntCounterVec,
+    pub monitor_restarts: IntCounterVec,
+    pub vehicle_data_errors: IntCounterVec,
+    pub storage_write_seconds: HistogramVec,
+    pub token_expires_in: IntGauge,
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+    let g = GaugeVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(g.clone())).unwrap();
+    g
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let c = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(c.clone())).unwrap();
+    c
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let r = Registry::new();
+        let vid = &["vehicle_id"];
+        let storage_write_seconds = HistogramVec::new(
+            HistogramOpts::new("tesla_storage_write_seconds", "storage write latency"),
+            &["table"],
+        )
+        .unwrap();
+        r.register(Box::new(storage_write_seconds.clone())).unwrap();
+        let token_expires_in =
+            IntGauge::new("tesla_token_expires_in_seconds", "access token expires in").unwrap();
+        r.register(Box::new(token_expires_in.clone())).unwrap();
+        Self {
+            battery_level: gauge(&r, "tesla_battery_level", "battery level %", vid),
+            battery_range: gauge(&r, "tesla_battery_range", "battery range (miles)", vid),
+            odometer: gauge(&r, "tesla_odometer", "odometer (miles)", vid),
+            charger_power: gauge(&r, "tesla_charger_power", "charger power (kW)", vid),
+            inside_temp: gauge(&r, "tesla_inside_temp", "inside temperature", vid),
+            outside_temp: gauge(&r, "tesla_outside_temp", "outside temperature", vid),
+            tpms_pressure: gauge(
+                &r,
+                "tesla_tpms_pressure",
+                "tire pressure (bar)",
+                &["vehicle_id", "wheel"],
+            ),
+            locked: gauge(&r, "tesla_locked", "vehicle locked", vid),
+            sentry_mode: gauge(&r, "tesla_sentry_mode", "sentry mode on", vid),
+            driving_state: gauge(
+                &r,
+                "tesla_driving_state",
+                "latest stream update",
+                &["vehicle_id", "field"],
+            ),
+            stream_reconnects: counter(
+                &r,
+                "tesla_stream_reconnects_total",
+                "stream reconnects",
+                vid,
+            ),
+            monitor_restarts: counter(
+                &r,
+                "tesla_monitor_restarts_total",
+                "vehicle monitor restarts after crash",
+                vid,
+            ),
+            vehicle_data_errors: counter(
+                &r,
+                "tesla_vehicle_data_errors_total",
+                "vehicle_data errors",
+                &["vehicle_id", "error"],
+            ),
+            storage_write_seconds,
+            token_expires_in,
+            registry: r,
+        }
+    }
+
+    pub fn observe_vehicle_data(&self, vid: i64, d: &VehicleData) {
+        let vid = vid.to_string();
+        let l = &[vid.as_str()];
+        if let Some(cs) = &d.charge_state {
+            self.battery_level
+                .with_label_values(l)
+                .set(cs.battery_level);
+            self.battery_range
+                .with_label_values(l)
+                .set(cs.battery_range);
+            self.charger_power
+                .with_label_values(l)
+                .set(cs.charger_power);
+        }
+        if let Some(cs) = &d.climate_state {
+            self.inside_temp.with_label_values(l).set(cs.inside_temp);
+            self.outside_temp.with_label_values(l).set(cs.outside_temp);
+        }
+        if let Some(vs) = &d.vehicle_state {
+            self.odometer.with_label_values(l).set(vs.odometer);
+            self.locked
+                .with_label_values(l)
+                .set(vs.locked as i32 as f64);
+            self.sentry_mode
+                .with_label_values(l)
+                .set(vs.sentry_mode as i32 as f64);
+            for (wheel, p) in [
+                ("fl", vs.tpms_pressure_fl),
+                ("fr", vs.tpms_pressure_fr),
+                ("rl", vs.tpms_pressure_rl),
+                ("rr", vs.tpms_pressure_rr),
+            ] {
+                self.tpms_pressure
+                    .with_label_values(&[vid.as_str(), wheel])
+                    .set(p);
+            }
+        }
+    }
+
+    pub fn observe_driving_state(&self, vid: i64, ds: &DrivingState) {
+        let vid = vid.to_string();
+        for (field, v) in [
+            ("speed", ds.speed),
+            ("odometer", ds.odometer),
+            ("soc", ds.soc),
+            ("elevation", ds.elevation),
+            ("heading", ds.heading),
+            ("latitude", ds.est_lat),
+            ("longitude", ds.est_lng),
+            ("power", ds.power),
+            ("range", ds.range),
+            ("est_range", ds.est_range),
+        ] {
+            self.driving_state
+                .with_label_values(&[vid.as_str(), field])
+                .set(v);
+        }
+    }
+
+    pub fn vehicle_data_error(&self, vid: i64, e: &tesla_api::Error) {
+        self.vehicle_data_errors
+            .with_label_values(&[&vid.to_string(), e.kind()])
+            .inc();
+    }
+
+    pub fn stream_reconnect(&self, vid: i64) {
+        self.stream_reconnects
+            .with_label_values(&[&vid.to_string()])
+            .inc();
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
@@ -0,0 +1,270 @@
+//! MQTT推送, 用于Home Assistant等家庭自动化系统
+//!
+//! * `{prefix}/{vehicle_id}/{charge_state|climate_state|...}/{field}` 每次vehicle_data快照
+//! * `{prefix}/{vehicle_id}/driving_state/{field}` 每次stream推送
+//! * `{prefix}/availability` 保留消息, online/offline(遗嘱消息)
+use base::pb::base::MqttConfig;
+use base::pb::tesla::*;
+use log::{error, info};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Home Assistant自动发现的传感器: (路径, 名称, 单位, device_class)
+const SENSORS: &[(&str, &str, &str, &str)] = &[
+    (
+        "charge_state/battery_level",
+        "Battery level",
+        "%",
+        "battery",
+    ),
+    (
+        "charge_state/battery_range",
+        "Battery range",
+        "mi",
+        "distance",
+    ),
+    ("charge_state/charger_power", "Charger power", "kW", "power"),
+    ("charge_state/charging_state", "Charging state", "", ""),
+    (
+        "charge_state/charge_energy_added",
+        "Energy added",
+        "kWh",
+        "energy",
+    ),
+    (
+        "climate_state/inside_temp",
+        "Inside temperature",
+        "°C",
+        "temperature",
+    ),
+    (
+        "climate_state/outside_temp",
+        "Outside temperature",
+        "°C",
+        "temperature",
+    ),
+    ("vehicle_state/odometer", "Odometer", "mi", "distance"),
+    ("vehicle_state/locked", "Locked", "", ""),
+    ("vehicle_state/sentry_mode", "Sentry mode", "", ""),
+    ("vehicle_state/car_version", "Software version", "", ""),
+    ("driving_state/speed", "Speed", "mph", "speed"),
+    ("driving_state/power", "Power", "kW", "power"),
+    ("state", "State", "", ""),
+];
+
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    prefix: String,
+    discovery_prefix: String,
+    discovered: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl MqttPublisher {
+    /// host为空时返回None
+    pub fn start(conf: &MqttConfig) -> Option<Self> {
+        if conf.host.is_empty() {
+            return None;
+        }
+        let prefix = if conf.topic_prefix.is_empty() {
+            "tesla".to_string()
+        } else {
+            conf.topic_prefix.clone()
+        };
+        let availability = format!("{prefix}/availability");
+        let client_id = if conf.client_id.is_empty() {
+            "tesla-app"
+        } else {
+            &conf.client_id
+        };
+        let port = if conf.port > 0 {
+            conf.port as u16
+        } else {
+            1883
+        };
+        let mut opts = MqttOptions::new(client_id, &conf.host, port);
+        opts.set_keep_alive(std::time::Duration::from_secs(30));
+        opts.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if !conf.username.is_empty() {
+            opts.set_credentials(&conf.username, &conf.password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(opts, 1000);
+        let publisher = Self {
+            client: client.clone(),
+            prefix,
+            discovery_prefix: conf.discovery_prefix.clone(),
+            discovered: Default::default(),
+        };
+        let discovered = Arc::clone(&publisher.discovered);
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("mqtt connected");
+                        // 重连后重新发布发现配置
+                        discovered.lock().unwrap().clear();
+                        if let Err(e) =
+                            client.try_publish(&availability, QoS::AtLeastOnce, true, "online")
+                        {
+                            error!("mqtt publish availability: {e}");
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        error!("mqtt: {e}");
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+        Some(publisher)
+    }
+
+    /// 不等待发送结果, 连接断开时丢弃, 避免阻塞VehicleMonitor
+    fn publish(&self, topic: String, retain: bool, payload: String) {
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, retain, payload)
+        {
+            error!("mqtt publish: {e}");
+        }
+    }
+
+    pub fn publish_vehicle_data(&self, vid: i64, d: &VehicleData) {
+        self.publish_discovery(vid, &display_name(d));
+        let value = serde_json::to_value(d).unwrap_or_default();
+        for (topic, payload) in flatten(&format!("{}/{vid}", self.prefix), &value) {
+            self.publish(topic, true, payload);
+        }
+    }
+
+    pub fn publish_driving_state(&self, vid: i64, ds: &DrivingState) {
+        let value = serde_json::to_value(ds).unwrap_or_default();
+        let prefix = format!("{}/{vid}/driving_state", self.prefix);
+        for (topic, payload) in flatten(&prefix, &value) {
+            self.publish(topic, false, payload);
+        }
+    }
+
+    fn publish_discovery(&self, vid: i64, name: &str) {
+        if self.discovery_prefix.is_empty() || !self.discovered.lock().unwrap().insert(vid) {
+            return;
+        }
+        let device = json!({
+            "identifiers": [format!("tesla_{vid}")],
+            "name": name,
+            "manufacturer": "Tesla",
+        });
+        for (path, sensor_name, unit, class) in SENSORS {
+            let object_id = format!("tesla_{vid}_{}", path.replace('/', "_"));
+            let mut config = json!({
+                "name": sensor_name,
+                "unique_id": object_id,
+                "state_topic": format!("{}/{vid}/{path}", self.prefix),
+                "availability_topic": format!("{}/availability", self.prefix),
+                "device": device,
+            });
+            if !unit.is_empty() {
+                config["unit_of_measurement"] = json!(unit);
+            }
+            if !class.is_empty() {
+                config["device_class"] = json!(class);
+            }
+            self.publish(
+                format!("{}/sensor/{object_id}/config", self.discovery_prefix),
+                true,
+                config.to_string(),
+            );
+        }
+    }
+}
+
+fn display_name(d: &VehicleData) -> String {
+    d.vehicle_state
+        .as_ref()
+        .map(|vs| vs.vehicle_name.clone())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| d.vin.clone())
+}
+
+/// 把json对象展开为 (topic, payload)
+pub fn flatten(prefix: &str, value: &Value) -> Vec<(String, String)> {
+    let mut v = vec![];
+    match value {
+        Value::Object(m) => {
+            for (k, x) in m.iter() {
+                v.extend(flatten(&format!("{prefix}/{k}"), x));
+            }
+        }
+        Value::Null => (),
+        Value::String(s) => v.push((prefix.to_string(), s.clone())),
+        _ => v.push((prefix.to_string(), value.to_string())),
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flatten_topics() {
+        let d = VehicleData {
+            state: "online".into(),
+            charge_state: Some(VehicleChargeState {
+                battery_level: 80.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let topics = flatten("tesla/1", &serde_json::to_value(&d).unwrap());
+        assert!(topics.contains(&("tesla/1/state".into(), "online".into())));
+        assert!(topics.contains(&("tesla/1/charge_state/battery_level".into(), "80.0".into())));
+        assert!(!topics
+            .iter()
+            .any(|(t, _)| t.starts_with("tesla/1/drive_state")));
+    }
+
+    /// 需要本地broker: docker run -p 1883:1883 eclipse-mosquitto mosquitto -c /mosquitto-no-auth.conf
+    #[tokio::test]
+    #[ignore]
+    async fn publish_to_local_broker() {
+        let publisher = MqttPublisher::start(&MqttConfig {
+            host: "localhost".into(),
+            client_id: "tesla-app-test".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut opts = MqttOptions::new("tesla-app-test-sub", "localhost", 1883);
+        opts.set_keep_alive(std::time::Duration::from_secs(5));
+        let (sub, mut eventloop) = AsyncClient::new(opts, 10);
+        sub.subscribe("tesla/1/driving_state/speed", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                match eventloop.poll().await.unwrap() {
+                    Event::Incoming(Packet::SubAck(_)) => publisher.publish_driving_state(
+                        1,
+                        &DrivingState {
+                            speed: 42.0,
+                            ..Default::default()
+                        },
+                    ),
+                    Event::Incoming(Packet::Publish(p)) => return p.payload,
+                    _ => (),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.as_ref(), b"42.0");
+    }
+}
@@ -0,0 +1,469 @@
+//! 通知规则, 每次vehicle_data快照和stream推送时检查, 发送到webhook/邮件/Telegram
+use crate::geofence;
+use crate::tpms::SlowLeak;
+use crate::Error;
+use base::pb::base::*;
+use base::pb::tesla::*;
+use futures_util::future::BoxFuture;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+
+const DEFAULT_COOLDOWN: i64 = 3600;
+const DEFAULT_UNLOCKED_DURATION: i64 = 600;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub kind: String,
+    pub vehicle_id: i64,
+    pub vehicle_name: String,
+    pub timestamp: i64,
+    pub message: String,
+}
+
+impl Notification {
+    fn title(&self) -> String {
+        format!("[{}] {}", self.vehicle_name, self.rule)
+    }
+}
+
+/// 通知发送渠道
+pub trait Sink: Send + Sync {
+    fn send<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// POST json到指定地址
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Sink for Webhook {
+    fn send<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(n)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+pub struct Telegram {
+    bot_token: String,
+    chat_id: String,
+    client: reqwest::Client,
+}
+
+impl Sink for Telegram {
+    fn send<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let url = format!("https://api.telegram.org/bot{}/sendMessage", self.bot_token);
+            self.client
+                .post(url)
+                .json(&json!({
+                    "chat_id": self.chat_id,
+                    "text": format!("{}\n{}", n.title(), n.message),
+                }))
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+pub struct Smtp {
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl Smtp {
+    fn new(conf: &NotifySink) -> Result<Self, Error> {
+        let err = |e: &dyn std::fmt::Display| Error::NotifyErr(format!("smtp {}: {e}", conf.name));
+        let port = if conf.smtp_port > 0 {
+            conf.smtp_port as u16
+        } else {
+            587
+        };
+        let builder = if port == 465 {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&conf.smtp_host)
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&conf.smtp_host)
+        }
+        .map_err(|e| err(&e))?
+        .port(port);
+        let builder = if conf.username.is_empty() {
+            builder
+        } else {
+            builder.credentials(Credentials::new(
+                conf.username.clone(),
+                conf.password.clone(),
+            ))
+        };
+        Ok(Self {
+            from: conf.from.parse().map_err(|e| err(&e))?,
+            to: conf
+                .to
+                .iter()
+                .map(|to| to.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| err(&e))?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Sink for Smtp {
+    fn send<'a>(&'a self, n: &'a Notification) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let err = |e: &dyn std::fmt::Display| Error::NotifyErr(format!("smtp: {e}"));
+            let mut builder = Message::builder()
+                .from(self.from.clone())
+                .subject(n.title());
+            for to in self.to.iter() {
+                builder = builder.to(to.clone());
+            }
+            let message = builder.body(n.message.clone()).map_err(|e| err(&e))?;
+            self.transport.send(message).await.map_err(|e| err(&e))?;
+            Ok(())
+        })
+    }
+}
+
+/// 按名称保存的发送渠道
+#[derive(Clone, Default)]
+pub struct Notifier {
+    sinks: Arc<Vec<(String, Box<dyn Sink>)>>,
+}
+
+impl Notifier {
+    /// webhook/telegram使用ApiConfig的超时和代理
+    pub fn new(conf: &NotifyConfig, api: &ApiConfig) -> Self {
+        let client = tesla_api::http_client(api)
+            .map_err(|e| error!("notify http client: {e}"))
+            .ok();
+        let mut sinks: Vec<(String, Box<dyn Sink>)> = vec![];
+        for s in conf.sinks.iter() {
+            let sink: Box<dyn Sink> = match (s.kind.as_str(), &client) {
+                ("webhook" | "telegram", None) => continue,
+                ("webhook", Some(client)) => Box::new(Webhook {
+                    url: s.url.clone(),
+                    client: client.clone(),
+                }),
+                ("telegram", Some(client)) => Box::new(Telegram {
+                    bot_token: s.bot_token.clone(),
+                    chat_id: s.chat_id.clone(),
+                    client: client.clone(),
+                }),
+                ("smtp", _) => match Smtp::new(s) {
+                    Ok(smtp) => Box::new(smtp),
+                    Err(e) => {
+                        error!("{e}");
+                        continue;
+                    }
+                },
+                (kind, _) => {
+                    error!("unknown notify sink kind={kind}");
+                    continue;
+                }
+            };
+            sinks.push((s.name.clone(), sink));
+        }
+        Self {
+            sinks: Arc::new(sinks),
+        }
+    }
+
+    /// 后台发送, 不阻塞调用方
+    pub fn send(&self, n: Notification, names: Vec<String>) {
+        let sinks = Arc::clone(&self.sinks);
+        tokio::spawn(async move {
+            info!("notify {}: {}", n.rule, n.message);
+            for (name, sink) in sinks.iter() {
+                if !names.is_empty() && !names.contains(name) {
+                    continue;
+                }
+                if let Err(e) = sink.send(&n).await {
+                    error!("notify sink {name}: {e}");
+                }
+            }
+        });
+    }
+}
+
+#[derive(Default, Clone)]
+struct RuleState {
+    /// 条件开始成立的时间(ms)
+    since: Option<i64>,
+    /// 本次条件成立期间已通知, 条件解除前不再重复
+    fired: bool,
+    last_sent: i64,
+}
+
+/// 单辆车的规则状态
+pub struct RuleEngine {
+    vehicle_id: i64,
+    rules: Vec<NotifyRule>,
+    states: Vec<RuleState>,
+    snapshot: Option<VehicleData>,
+    position: Option<(f64, f64)>,
+    was_charging: bool,
+    last_state: String,
+    state_changed: Option<String>,
+    slow_leaks: Vec<SlowLeak>,
+}
+
+impl RuleEngine {
+    pub fn new(vehicle_id: i64, conf: &NotifyConfig) -> Self {
+        Self {
+            vehicle_id,
+            rules: conf.rules.clone(),
+            states: conf.rules.iter().map(|_| RuleState::default()).collect(),
+            snapshot: None,
+            position: None,
+            was_charging: false,
+            last_state: String::new(),
+            state_changed: None,
+            slow_leaks: vec![],
+        }
+    }
+
+    /// 配置重新加载时替换规则, 未修改的规则保留触发状态
+    pub fn set_rules(&mut self, rules: &[NotifyRule]) {
+        self.states = rules
+            .iter()
+            .map(|r| match self.rules.iter().position(|old| old == r) {
+                Some(i) => self.states[i].clone(),
+                None => RuleState::default(),
+            })
+            .collect();
+        self.rules = rules.to_vec();
+    }
+
+    /// 每天检测一次, 下次快照时检查规则
+    pub fn set_slow_leaks(&mut self, leaks: Vec<SlowLeak>) {
+        self.slow_leaks = leaks;
+    }
+
+    pub fn on_vehicle_data(
+        &mut self,
+        now: i64,
+        d: &VehicleData,
+        geofences: &[Geofence],
+    ) -> Vec<(Notification, Vec<String>)> {
+        if let Some(cs) = &d.charge_state {
+            match cs.charging_state.as_str() {
+                "Charging" => self.was_charging = true,
+                "Stopped" => (),
+                _ => self.was_charging = false,
+            }
+        }
+        self.state_changed = None;
+        if !self.last_state.is_empty() && self.last_state != d.state {
+            self.state_changed = Some(format!("State {} -> {}", self.last_state, d.state));
+        }
+        self.last_state = d.state.clone();
+        self.snapshot = Some(d.clone());
+        self.evaluate(now, geofences)
+    }
+
+    pub fn on_driving_state(
+        &mut self,
+        now: i64,
+        ds: &DrivingState,
+        geofences: &[Geofence],
+    ) -> Vec<(Notification, Vec<String>)> {
+        if ds.est_lat != 0.0 || ds.est_lng != 0.0 {
+            self.position = Some((ds.est_lat, ds.est_lng));
+        }
+        self.evaluate(now, geofences)
+    }
+
+    /// 条件成立时返回消息
+    fn condition(&self, rule: &NotifyRule, geofences: &[Geofence]) -> Option<String> {
+        let d = self.snapshot.as_ref()?;
+        let vs = d.vehicle_state.as_ref();
+        match rule.kind.as_str() {
+            "charging_stopped" => {
+                let cs = d.charge_state.as_ref()?;
+                (self.was_charging
+                    && cs.charging_state == "Stopped"
+                    && cs.battery_level < cs.charge_limit_soc)
+                    .then(|| {
+                        format!(
+                            "Charging stopped at {}% (limit {}%)",
+                            cs.battery_level, cs.charge_limit_soc
+                        )
+                    })
+            }
+            "unlocked" => vs
+                .filter(|vs| !vs.locked && !vs.is_user_present)
+                .map(|_| "Vehicle is unlocked".to_string()),
+            "sentry_off_away" => {
+                let vs = vs?;
+                let (latitude, longitude) = self.position?;
+                let home = if rule.geofence.is_empty() {
+                    "Home"
+                } else {
+                    &rule.geofence
+                };
+                let fence = geofences.iter().find(|g| g.name == home)?;
+                (vs.sentry_mode_available
+                    && !vs.sentry_mode
+                    && !geofence::contains(fence, latitude, longitude))
+                .then(|| format!("Sentry mode is off away from {home}"))
+            }
+            "software_update" => vs
+                .and_then(|vs| vs.software_update.as_ref())
+                .filter(|u| !u.status.is_empty())
+                .map(|u| format!("Software update {} {}", u.version, u.status)),
+            "tpms_soft_warning" => {
+                let vs = vs?;
+                let wheels: Vec<_> = [
+                    ("FL", vs.tpms_soft_warning_fl),
+                    ("FR", vs.tpms_soft_warning_fr),
+                    ("RL", vs.tpms_soft_warning_rl),
+                    ("RR", vs.tpms_soft_warning_rr),
+                ]
+                .into_iter()
+                .filter(|(_, w)| *w)
+                .map(|(wheel, _)| wheel)
+                .collect();
+                (!wheels.is_empty())
+                    .then(|| format!("Tire pressure warning: {}", wheels.join(", ")))
+            }
+            "tpms_slow_leak" => (!self.slow_leaks.is_empty()).then(|| {
+                let wheels: Vec<_> = self
+                    .slow_leaks
+                    .iter()
+                    .map(|l| format!("{} {:.2}bar", l.wheel.to_uppercase(), l.drop))
+                    .collect();
+                format!("Possible slow leak: {}", wheels.join(", "))
+            }),
+            "state_changed" => self.state_changed.clone(),
+            _ => None,
+        }
+    }
+
+    fn evaluate(&mut self, now: i64, geofences: &[Geofence]) -> Vec<(Notification, Vec<String>)> {
+        let mut v = vec![];
+        for i in 0..self.rules.len() {
+            let rule = &self.rules[i];
+            let message = self.condition(rule, geofences);
+            let state = &mut self.states[i];
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    state.since = None;
+                    state.fired = false;
+                    continue;
+                }
+            };
+            let since = *state.since.get_or_insert(now);
+            let duration = match (rule.duration, rule.kind.as_str()) {
+                (0, "unlocked") => DEFAULT_UNLOCKED_DURATION,
+                (duration, _) => duration,
+            };
+            let cooldown = if rule.cooldown > 0 {
+                rule.cooldown
+            } else {
+                DEFAULT_COOLDOWN
+            };
+            if state.fired
+                || now - since < duration * 1000
+                || (state.last_sent > 0 && now - state.last_sent < cooldown * 1000)
+            {
+                continue;
+            }
+            state.fired = true;
+            state.last_sent = now;
+            let vehicle_name = self
+                .snapshot
+                .as_ref()
+                .and_then(|d| d.vehicle_state.as_ref())
+                .map(|vs| vs.vehicle_name.clone())
+                .filter(|n| !n.is_empty())
+                .unwrap_or_else(|| self.vehicle_id.to_string());
+            v.push((
+                Notification {
+                    rule: if rule.name.is_empty() {
+                        rule.kind.clone()
+                    } else {
+                        rule.name.clone()
+                    },
+                    kind: rule.kind.clone(),
+                    vehicle_id: self.vehicle_id,
+                    vehicle_name,
+                    timestamp: now,
+                    message,
+                },
+                rule.sinks.clone(),
+            ));
+        }
+        // 状态变化只在当次快照有效
+        self.state_changed = None;
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlocked(locked: bool) -> VehicleData {
+        VehicleData {
+            state: "online".into(),
+            vehicle_state: Some(VehicleState {
+                locked,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unlocked_duration_and_cooldown() {
+        let mut e = RuleEngine::new(
+            1,
+            &NotifyConfig {
+                rules: vec![NotifyRule {
+                    kind: "unlocked".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        );
+        let min = 60 * 1000;
+        assert!(e.on_vehicle_data(0, &unlocked(false), &[]).is_empty());
+        assert!(e.on_vehicle_data(5 * min, &unlocked(false), &[]).is_empty());
+        assert_eq!(e.on_vehicle_data(10 * min, &unlocked(false), &[]).len(), 1);
+        // 持续成立不重复通知
+        assert!(e
+            .on_vehicle_data(30 * min, &unlocked(false), &[])
+            .is_empty());
+        assert!(e.on_vehicle_data(31 * min, &unlocked(true), &[]).is_empty());
+        // 冷却时间内不通知
+        assert!(e
+            .on_vehicle_data(32 * min, &unlocked(false), &[])
+            .is_empty());
+        assert!(e
+            .on_vehicle_data(45 * min, &unlocked(false), &[])
+            .is_empty());
+        assert!(e.on_vehicle_data(71 * min, &unlocked(true), &[]).is_empty());
+        assert!(e
+            .on_vehicle_data(72 * min, &unlocked(false), &[])
+            .is_empty());
+        assert_eq!(e.on_vehicle_data(82 * min, &unlocked(false), &[]).len(), 1);
+    }
+}
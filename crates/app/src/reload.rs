@@ -0,0 +1,134 @@
+//! 配置热加载, 配置文件变化或收到SIGHUP时重新读取, 校验通过后整体替换并通知监控和HTTP服务
+//! 轮询间隔/围栏/电价/通知规则立即生效, 地址/端口/目录等需要重启
+use ::notify::Watcher;
+use base::pb::base::AppConfig;
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+pub type ConfigReceiver = watch::Receiver<Arc<AppConfig>>;
+
+/// 需要重启才能生效的字段沿用旧值, 返回被忽略的字段名
+fn keep_restart_fields(old: &AppConfig, new: &mut AppConfig) -> Vec<&'static str> {
+    let mut ignored = vec![];
+    macro_rules! keep {
+        ($($field:ident),*) => {
+            $(
+                if old.$field != new.$field {
+                    ignored.push(stringify!($field));
+                    new.$field = old.$field.clone();
+                }
+            )*
+        };
+    }
+    keep!(
+        pika_address,
+        redis_address,
+        http_port,
+        https_port,
+        grpc_port,
+        api_config,
+        geocoder,
+        mqtt,
+        data_dir,
+        static_dir
+    );
+    ignored
+}
+
+/// 重新加载并下发, 配置无效时保留当前配置; 有变化时返回true
+pub fn reload(path: Option<&str>, tx: &watch::Sender<Arc<AppConfig>>) -> Result<bool, base::Error> {
+    let mut conf = AppConfig::load(path)?;
+    let current = tx.borrow().clone();
+    let ignored = keep_restart_fields(&current, &mut conf);
+    if !ignored.is_empty() {
+        warn!("config reload: {} 需要重启后生效", ignored.join(","));
+    }
+    if conf == *current {
+        return Ok(false);
+    }
+    tx.send_replace(Arc::new(conf));
+    Ok(true)
+}
+
+/// 监听配置文件和SIGHUP
+pub fn spawn(path: Option<String>, tx: watch::Sender<Arc<AppConfig>>) {
+    let (fs_tx, mut fs_rx) = tokio::sync::mpsc::unbounded_channel();
+    // 编辑器保存时常先写临时文件再改名, 所以监听所在目录
+    let watcher = path.as_ref().and_then(|path| {
+        let path = std::path::Path::new(path);
+        let name = path.file_name()?.to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+        let mut watcher =
+            ::notify::recommended_watcher(move |e: ::notify::Result<::notify::Event>| {
+                if let Ok(e) = e {
+                    if e.paths
+                        .iter()
+                        .any(|p| p.file_name() == Some(name.as_os_str()))
+                    {
+                        let _ = fs_tx.send(());
+                    }
+                }
+            })
+            .map_err(|e| error!("config watcher: {e}"))
+            .ok()?;
+        watcher
+            .watch(&dir, ::notify::RecursiveMode::NonRecursive)
+            .map_err(|e| error!("watch {}: {e}", dir.display()))
+            .ok()?;
+        Some(watcher)
+    });
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        let mut hup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        loop {
+            tokio::select! {
+                _ = hup.recv() => info!("SIGHUP, reload config"),
+                Some(()) = fs_rx.recv() => {
+                    // 合并一次保存产生的多个事件
+                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                    while fs_rx.try_recv().is_ok() {}
+                    info!("config file changed, reload");
+                }
+            }
+            match reload(path.as_deref(), &tx) {
+                Ok(true) => info!("config reloaded"),
+                Ok(false) => info!("config unchanged"),
+                Err(e) => error!("config reload rejected, keep current: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_keeps_old_on_error() {
+        let path = std::env::temp_dir().join(format!("tesla-reload-{}.json", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, r#"{"tariffs": [{"price": 1.0}]}"#).unwrap();
+        let (tx, rx) = watch::channel(Arc::new(AppConfig::load(Some(path_str)).unwrap()));
+
+        std::fs::write(
+            &path,
+            r#"{"tariffs": [{"price": 2.0}], "http_port": 1234, "poll_interval": 30}"#,
+        )
+        .unwrap();
+        assert!(reload(Some(path_str), &tx).unwrap());
+        let conf = rx.borrow().clone();
+        assert_eq!(conf.tariffs[0].price, 2.0);
+        assert_eq!(conf.poll_interval, 30);
+        assert_eq!(conf.http_port, 3600);
+
+        std::fs::write(&path, r#"{"pika_address": "bad"}"#).unwrap();
+        assert!(reload(Some(path_str), &tx).is_err());
+        assert_eq!(rx.borrow().tariffs[0].price, 2.0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
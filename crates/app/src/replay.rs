@@ -0,0 +1,197 @@
+//! 回放录制文件, 按录制时的间隔(可加速)送入VehicleMonitor的处理流程, 用于离线排查行程/充电识别
+use crate::geocoder::Geocoder;
+use crate::vehicle_monitor::{handle_frame, MonitorState};
+use crate::{charging, trip, Error};
+use base::pb::base::AppConfig;
+use base::pb::tesla::*;
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct VehiclesResponse {
+    response: Vec<Vehicle>,
+}
+
+#[derive(Deserialize)]
+struct VehicleDataResponse {
+    response: Option<VehicleData>,
+}
+
+/// 每辆车回放的推送和快照数量, 以及从回放的区间数据识别出的行程和充电
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplayStats {
+    pub updates: usize,
+    pub snapshots: usize,
+    pub trips: Vec<Trip>,
+    pub charges: Vec<HistoryCharge>,
+}
+
+/// /api/1/vehicles/{id}/vehicle_data中的id
+fn vehicle_data_id(path: &str) -> Option<i64> {
+    path.strip_prefix("/api/1/vehicles/")?
+        .strip_suffix("/vehicle_data")?
+        .parse()
+        .ok()
+}
+
+async fn state<'a>(
+    states: &'a mut HashMap<i64, MonitorState>,
+    vehicle_id: i64,
+    conf: &AppConfig,
+) -> &'a mut MonitorState {
+    match states.entry(vehicle_id) {
+        Entry::Occupied(e) => e.into_mut(),
+        Entry::Vacant(e) => {
+            // 回放时不解析行程地址
+            let geocoder = Arc::new(Geocoder::default());
+            e.insert(MonitorState::new(vehicle_id, conf.clone(), None, geocoder).await)
+        }
+    }
+}
+
+/// speed为加速倍数, 1为按录制时的速度, 0为不等待
+/// 回放时不发送通知和MQTT, 结果写入pika指定的库; 区间数据按录制时间保存, 不能写入正在使用的库
+pub async fn replay(
+    path: &str,
+    speed: f64,
+    pika: &str,
+    conf: &AppConfig,
+) -> Result<HashMap<i64, ReplayStats>, Error> {
+    if pika.trim_end_matches('/') == conf.pika_address.trim_end_matches('/') {
+        return Err(Error::ArgErr(format!(
+            "replay would overwrite records in {pika}, use another pika"
+        )));
+    }
+    let entries = tesla_api::record::load(path)?;
+    info!("replay {} entries from {path} into {pika}", entries.len());
+    let conf = AppConfig {
+        pika_address: pika.to_string(),
+        notify: None,
+        mqtt: None,
+        ..conf.clone()
+    };
+    // 保存过的区间数据, 回放结束后识别行程和充电
+    let mut records: HashMap<i64, Vec<VehiclePeriodRecord>> = HashMap::new();
+    let mut states: HashMap<i64, MonitorState> = HashMap::new();
+    let mut stats: HashMap<i64, ReplayStats> = HashMap::new();
+    // id -> vehicle_id
+    let mut ids: HashMap<i64, i64> = HashMap::new();
+    let mut last = None;
+    for entry in entries.iter() {
+        if let Some(last) = last.filter(|_| speed > 0.0) {
+            let wait = (entry.timestamp - last) as f64 / speed;
+            if wait > 0.0 {
+                tokio::time::sleep(std::time::Duration::from_millis(wait as u64)).await;
+            }
+        }
+        last = Some(entry.timestamp);
+        match entry.kind.as_str() {
+            "ws" => {
+                let vehicle_id = match entry.path.parse::<i64>() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                if let Some(update) = handle_frame(entry.body.as_bytes()) {
+                    state(&mut states, vehicle_id, &conf)
+                        .await
+                        .on_driving_state(entry.timestamp, update);
+                    stats.entry(vehicle_id).or_default().updates += 1;
+                }
+            }
+            "http" if entry.path == "/api/1/vehicles" && entry.status == 200 => {
+                match serde_json::from_str::<VehiclesResponse>(&entry.body) {
+                    Ok(resp) => ids.extend(resp.response.iter().map(|v| (v.id, v.vehicle_id))),
+                    Err(e) => error!("replay vehicles: {e}"),
+                }
+            }
+            "http" => {
+                let id = match vehicle_data_id(&entry.path) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let d = serde_json::from_str::<VehicleDataResponse>(&entry.body)
+                    .ok()
+                    .and_then(|r| r.response)
+                    .filter(|_| entry.status == 200);
+                if let Some(d) = &d {
+                    ids.insert(id, d.vehicle_id);
+                }
+                // 和实时监控一样, 每次请求vehicle_data后保存区间数据
+                let vehicle_id = match ids.get(&id) {
+                    Some(v) => *v,
+                    None => continue,
+                };
+                let state = state(&mut states, vehicle_id, &conf).await;
+                if let Some(d) = d {
+                    state.on_vehicle_data(entry.timestamp, d);
+                    stats.entry(vehicle_id).or_default().snapshots += 1;
+                }
+                save(state, records.entry(vehicle_id).or_default()).await;
+            }
+            _ => (),
+        }
+    }
+    for (vehicle_id, state) in states.iter_mut() {
+        save(state, records.entry(*vehicle_id).or_default()).await;
+    }
+    for (vehicle_id, mut records) in records {
+        records.sort_by_key(|r| r.timestamp);
+        let s = stats.entry(vehicle_id).or_default();
+        s.trips = trip::detect_trips(&records);
+        s.charges = charging::detect_sessions(&records);
+    }
+    Ok(stats)
+}
+
+async fn save(state: &mut MonitorState, records: &mut Vec<VehiclePeriodRecord>) {
+    let pr = state.period_record();
+    if pr.timestamp != 0 {
+        records.push(pr.clone());
+    }
+    state.save().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tesla_api::record::Recorder;
+    use tesla_mock::{MockServer, Scenario};
+
+    /// 录制模拟服务的请求和推送, 再全速回放
+    #[tokio::test]
+    async fn record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("tesla-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("record.jsonl").to_string_lossy().to_string();
+        let scenario = Scenario::drive();
+        let expected = scenario.updates.len();
+        let server = MockServer::start(scenario).await;
+        let api = server
+            .api_client()
+            .await
+            .with_recorder(Some(Arc::new(Recorder::create(&path).unwrap())));
+        let vehicle = api.vehicles().await.unwrap().remove(0);
+        base::check_make_dir(&base::data_path(&format!("{}/logs", vehicle.vehicle_id)));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        api.stream(vehicle.vehicle_id, &tx).await.unwrap();
+        while rx.try_recv().is_ok() {}
+        api.vehicle_data(vehicle.id).await.unwrap();
+
+        let conf = AppConfig {
+            pika_address: "redis://127.0.0.1:1/".into(),
+            ..Default::default()
+        };
+        assert!(replay(&path, 0.0, &conf.pika_address, &conf).await.is_err());
+        let stats = replay(&path, 0.0, "redis://127.0.0.1:2/", &conf)
+            .await
+            .unwrap();
+        let s = &stats[&vehicle.vehicle_id];
+        assert_eq!((s.updates, s.snapshots), (expected, 1));
+        assert!(s.charges.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! 按保存的区间数据重新生成行程/充电/电池健康度/事件/软件升级/胎压等派生数据
+//! 先删除有区间数据的日期内的旧数据再保存, 可重复执行; 没有区间数据的日期(如从TeslaMate导入的)保持不变
+use crate::events::EventExtractor;
+use crate::geocoder::Geocoder;
+use crate::geofence::{self, GeofenceTracker};
+use crate::software_update::SoftwareUpdateTracker;
+use crate::{battery, charging, tpms, trip, Error};
+use base::pb::base::{AppConfig, Geofence, GeofenceEvent};
+use base::pb::tesla::*;
+use chrono::{NaiveDate, TimeZone};
+use db::pika::PikaConnection;
+use log::info;
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub days: usize,
+    pub records: usize,
+    pub trips: usize,
+    pub charges: usize,
+    pub battery_health: usize,
+    pub events: usize,
+    pub geofence_events: usize,
+    pub software_updates: usize,
+    pub tpms_days: usize,
+}
+
+/// yyyymmdd当天0点的本地时间戳(ms)
+fn day_start(day: NaiveDate) -> i64 {
+    chrono::Local
+        .from_local_datetime(&day.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_default()
+}
+
+fn parse_day(day: i32) -> Result<NaiveDate, Error> {
+    NaiveDate::parse_from_str(&day.to_string(), "%Y%m%d")
+        .map_err(|e| Error::ArgErr(format!("invalid day {day}: {e}")))
+}
+
+/// 有区间数据的日期和对应的[from, to)毫秒范围, 没有区间数据的日期不在其中
+fn ranges(days: &[(i32, Vec<VehiclePeriodRecord>)]) -> Result<Vec<(i32, i64, i64)>, Error> {
+    days.iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(day, _)| {
+            let date = parse_day(*day)?;
+            Ok((*day, day_start(date), day_start(date.succ_opt().unwrap())))
+        })
+        .collect()
+}
+
+/// 重新生成需要的已保存数据
+#[derive(Default)]
+struct Stored {
+    trips: Vec<Trip>,
+    software_updates: Vec<SoftwareUpdateRecord>,
+    geofences: Vec<Geofence>,
+    bills: Vec<SuperchargerBill>,
+}
+
+/// 由区间数据生成的派生数据, 同样的输入结果相同
+#[derive(Debug, Default, PartialEq)]
+struct Derived {
+    trips: Vec<Trip>,
+    charges: Vec<HistoryCharge>,
+    battery_health: Vec<BatteryHealth>,
+    events: Vec<VehicleEvent>,
+    geofence_events: Vec<GeofenceEvent>,
+    software_updates: Vec<SoftwareUpdateRecord>,
+    tpms: Vec<TpmsDaily>,
+}
+
+/// start为第一个有区间数据的日期0点(ms)
+async fn derive(
+    days: &[(i32, Vec<VehiclePeriodRecord>)],
+    start: i64,
+    stored: &Stored,
+    conf: &AppConfig,
+    geocoder: &Geocoder,
+) -> Derived {
+    let mut d = Derived::default();
+    let records: Vec<VehiclePeriodRecord> =
+        days.iter().flat_map(|(_, v)| v.iter().cloned()).collect();
+
+    d.trips = trip::detect_trips(&records);
+    for t in d.trips.iter_mut() {
+        trip::fill(t, &stored.trips, &stored.geofences, geocoder).await;
+    }
+
+    d.charges = charging::detect_sessions(&records);
+    charging::fill(
+        &mut d.charges,
+        &stored.bills,
+        &stored.geofences,
+        &conf.tariffs,
+    );
+    d.battery_health = d.charges.iter().filter_map(battery::estimate).collect();
+
+    let mut event_extractor = EventExtractor::default();
+    // 空坐标不会记为所在围栏, 第一个有效位置只作为基准, 见GeofenceTracker::update
+    let mut geofence_tracker = GeofenceTracker::default();
+    let mut software_update_tracker = SoftwareUpdateTracker::new(
+        stored
+            .software_updates
+            .iter()
+            .rev()
+            .find(|u| u.end_timestamp < start)
+            .map(|u| u.version.as_str())
+            .unwrap_or_default(),
+    );
+    for pr in records.iter() {
+        for ds in pr.updates.iter() {
+            d.geofence_events.extend(geofence_tracker.update(
+                &stored.geofences,
+                ds.timestamp,
+                ds.est_lat,
+                ds.est_lng,
+            ));
+        }
+        let vs = match pr.snapshot.as_ref().and_then(|d| d.vehicle_state.as_ref()) {
+            Some(vs) => vs,
+            None => continue,
+        };
+        let now = pr.timestamp * 1000;
+        d.events.extend(event_extractor.update(now, vs));
+        d.software_updates
+            .extend(software_update_tracker.update(now, vs));
+    }
+
+    for (day, v) in days.iter() {
+        let samples: Vec<_> = v
+            .iter()
+            .filter_map(|pr| tpms::sample(pr.timestamp * 1000, pr.snapshot.as_ref()?))
+            .collect();
+        if !samples.is_empty() {
+            d.tpms.push(tpms::daily(*day, &samples));
+        }
+    }
+    d
+}
+
+/// from/to为yyyymmdd, 包含to当天
+pub async fn reprocess(
+    conf: &AppConfig,
+    geocoder: &Geocoder,
+    vid: i64,
+    from: i32,
+    to: i32,
+) -> Result<Summary, Error> {
+    parse_day(from)?;
+    parse_day(to)?;
+    let mut summary = Summary::default();
+    let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+    let days = pika
+        .load_vehicle_period_records_by_day(vid, from, to)
+        .await?;
+    let ranges = ranges(&days)?;
+    let start = match ranges.first() {
+        Some((_, start, _)) => *start,
+        None => {
+            info!("reprocess vehicle={vid} no records in {from}..={to}");
+            return Ok(summary);
+        }
+    };
+    // 地址解析结果复用已保存的行程, 软件版本从区间之前的最后一次升级开始比较
+    let stored = Stored {
+        trips: pika.load_trips(vid).await?,
+        software_updates: pika.load_software_updates(vid).await?,
+        geofences: geofence::load_all(conf, &mut pika).await?,
+        bills: pika.load_supercharger_bills(vid).await?,
+    };
+    for (day, from, to) in ranges.iter() {
+        pika.delete_derived(vid, *from, *to).await?;
+        pika.delete_tpms_daily(vid, *day, *day).await?;
+    }
+    summary.days = ranges.len();
+    summary.records = days.iter().map(|(_, v)| v.len()).sum();
+
+    let d = derive(&days, start, &stored, conf, geocoder).await;
+    for t in d.trips.iter() {
+        pika.save_trip(vid, t).await?;
+    }
+    for charge in d.charges.iter() {
+        pika.save_charge(vid, charge).await?;
+    }
+    for health in d.battery_health.iter() {
+        pika.save_battery_health(vid, health).await?;
+    }
+    for event in d.geofence_events.iter() {
+        pika.save_geofence_event(vid, event).await?;
+    }
+    for event in d.events.iter() {
+        pika.save_vehicle_event(vid, event).await?;
+    }
+    for update in d.software_updates.iter() {
+        pika.save_software_update(vid, update).await?;
+    }
+    for daily in d.tpms.iter() {
+        pika.save_tpms_daily(vid, daily).await?;
+    }
+    summary.trips = d.trips.len();
+    summary.charges = d.charges.len();
+    summary.battery_health = d.battery_health.len();
+    summary.geofence_events = d.geofence_events.len();
+    summary.events = d.events.len();
+    summary.software_updates = d.software_updates.len();
+    summary.tpms_days = d.tpms.len();
+    info!("reprocess vehicle={vid} {from}..={to} {:?}", summary);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-02当天: 停在Home(第一条推送坐标为空), 出发离开, 到达后充电, 期间锁车
+    fn fixture() -> Vec<(i32, Vec<VehiclePeriodRecord>)> {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+        let base = day_start(date) / 1000 + 8 * 3600;
+        let record = |i: i64, state: &str, lat: f64, charging: bool, locked: bool| {
+            let ts = base + i * 600;
+            VehiclePeriodRecord {
+                timestamp: ts,
+                updates: vec![DrivingState {
+                    timestamp: ts * 1000,
+                    est_lat: lat,
+                    est_lng: if lat == 0.0 { 0.0 } else { 121.4737 },
+                    odometer: 100.0 + i as f64,
+                    ..Default::default()
+                }],
+                snapshot: Some(VehicleData {
+                    state: state.into(),
+                    charge_state: Some(VehicleChargeState {
+                        charging_state: if charging { "Charging" } else { "Stopped" }.into(),
+                        battery_level: 50.0 + i as f64,
+                        usable_battery_level: 50.0 + i as f64,
+                        battery_range: 150.0 + i as f64 * 3.0,
+                        ..Default::default()
+                    }),
+                    vehicle_state: Some(VehicleState {
+                        car_version: "2024.2.7".into(),
+                        locked,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+            }
+        };
+        vec![
+            // 从TeslaMate导入的日期没有区间数据
+            (20240101, vec![]),
+            (
+                20240102,
+                vec![
+                    record(0, "online", 0.0, false, true),
+                    record(1, "online", 31.2304, false, false),
+                    record(2, "online", 31.30, false, false),
+                    record(3, "offline", 31.30, true, true),
+                    record(4, "offline", 31.30, true, true),
+                    record(5, "offline", 31.30, false, true),
+                ],
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn derive_is_idempotent() {
+        let days = fixture();
+        let ranges = ranges(&days).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].0, 20240102);
+        let start = ranges[0].1;
+        let mut stored = Stored {
+            geofences: vec![Geofence {
+                name: "Home".into(),
+                latitude: 31.2304,
+                longitude: 121.4737,
+                radius: 200.0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let conf = AppConfig::default();
+        let geocoder = Geocoder::default();
+        let first = derive(&days, start, &stored, &conf, &geocoder).await;
+        assert_eq!(first.trips.len(), 1);
+        assert_eq!(first.charges.len(), 1);
+        // 第一条推送坐标为空, 第一个有效位置在Home内, 只产生离开事件
+        let fences: Vec<_> = first
+            .geofence_events
+            .iter()
+            .map(|e| (e.name.as_str(), e.event.as_str()))
+            .collect();
+        assert_eq!(fences, vec![("Home", "exit")]);
+        assert_eq!(first.events.len(), 2);
+        assert!(first.software_updates.is_empty());
+
+        // 再次执行时已保存的行程和升级记录来自上一次的结果
+        stored.trips = first.trips.clone();
+        stored.software_updates = first.software_updates.clone();
+        let second = derive(&days, start, &stored, &conf, &geocoder).await;
+        assert_eq!(first, second);
+        // 删除范围只包括有区间数据的当天
+        assert!(first
+            .trips
+            .iter()
+            .all(|t| ranges[0].1 <= t.timestamp && t.timestamp < ranges[0].2));
+    }
+}
@@ -0,0 +1,20 @@
+//! 有序退出: 收到SIGTERM/SIGINT后停止HTTP/gRPC服务, 各车辆监控保存未写入的区间数据并关闭stream
+use log::info;
+use tokio::sync::watch;
+
+pub type ShutdownReceiver = watch::Receiver<bool>;
+
+/// 等待SIGTERM(docker stop)或Ctrl-C
+pub async fn signal() {
+    let mut term =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = term.recv() => info!("SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("SIGINT, shutting down"),
+    }
+}
+
+/// 开始退出时返回, 发送端关闭也视为退出
+pub async fn wait(mut rx: ShutdownReceiver) {
+    let _ = rx.wait_for(|v| *v).await;
+}
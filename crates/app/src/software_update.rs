@@ -0,0 +1,113 @@
+//! 软件版本变化检测, 根据vehicle_data快照中的car_version和software_update
+use base::pb::tesla::*;
+
+#[derive(Default)]
+pub struct SoftwareUpdateTracker {
+    version: String,
+    /// 最后一次看到旧版本的时间
+    last_seen: i64,
+    available_timestamp: i64,
+    start_timestamp: i64,
+}
+
+impl SoftwareUpdateTracker {
+    /// version为已保存的当前版本, 重启后版本变化仍能检测到
+    pub fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// 当前版本, 需要保存以便重启后恢复
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// 版本变化时返回升级记录
+    pub fn update(&mut self, timestamp: i64, vs: &VehicleState) -> Option<SoftwareUpdateRecord> {
+        if vs.car_version.is_empty() {
+            return None;
+        }
+        if let Some(u) = &vs.software_update {
+            match u.status.as_str() {
+                "available" | "scheduled" | "downloading" | "downloading_wifi_wait" => {
+                    if self.available_timestamp == 0 {
+                        self.available_timestamp = timestamp;
+                    }
+                }
+                "installing" => {
+                    if self.start_timestamp == 0 {
+                        self.start_timestamp = timestamp;
+                    }
+                }
+                _ => (),
+            }
+        }
+        if vs.car_version == self.version {
+            self.last_seen = timestamp;
+            return None;
+        }
+        let from_version = std::mem::replace(&mut self.version, vs.car_version.clone());
+        let record = SoftwareUpdateRecord {
+            from_version,
+            version: vs.car_version.clone(),
+            available_timestamp: self.available_timestamp,
+            start_timestamp: if self.start_timestamp > 0 {
+                self.start_timestamp
+            } else {
+                self.last_seen
+            },
+            end_timestamp: timestamp,
+            odometer: vs.odometer,
+        };
+        self.last_seen = timestamp;
+        self.available_timestamp = 0;
+        self.start_timestamp = 0;
+        // 首次启动没有已知版本, 只记录当前版本
+        (!record.from_version.is_empty()).then_some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vs(version: &str, status: &str) -> VehicleState {
+        VehicleState {
+            car_version: version.into(),
+            software_update: Some(SoftwareUpdate {
+                status: status.into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detect_version_change() {
+        let mut t = SoftwareUpdateTracker::default();
+        assert!(t.update(1, &vs("2023.44.30", "")).is_none());
+        assert!(t.update(2, &vs("2023.44.30", "available")).is_none());
+        assert!(t.update(3, &vs("2023.44.30", "installing")).is_none());
+        let r = t.update(4, &vs("2024.2.7", "")).unwrap();
+        assert_eq!(r.from_version, "2023.44.30");
+        assert_eq!(r.version, "2024.2.7");
+        assert_eq!(
+            (r.available_timestamp, r.start_timestamp, r.end_timestamp),
+            (2, 3, 4)
+        );
+        assert!(t.update(5, &vs("2024.2.7", "")).is_none());
+    }
+
+    #[test]
+    fn upgrade_while_stopped() {
+        let mut t = SoftwareUpdateTracker::default();
+        assert!(t.update(1, &vs("2023.44.30", "")).is_none());
+        // 重启后从保存的当前版本恢复, 停机期间的升级仍能检测到
+        let mut t = SoftwareUpdateTracker::new(t.version());
+        let r = t.update(2, &vs("2024.2.7", "")).unwrap();
+        assert_eq!(r.from_version, "2023.44.30");
+        assert_eq!(r.version, "2024.2.7");
+    }
+}
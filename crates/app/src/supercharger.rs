@@ -0,0 +1,230 @@
+//! 超充账单同步, 按VIN和时间关联本地识别的充电过程
+use crate::reload::ConfigReceiver;
+use crate::shutdown::{self, ShutdownReceiver};
+use crate::Error;
+use base::pb::base::AppConfig;
+use base::pb::tesla::*;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use db::pika::PikaConnection;
+use log::{error, info};
+use tesla_api::ApiClient;
+
+/// 账单和充电过程的时间允许误差(ms)
+const TOLERANCE: i64 = 10 * 60 * 1000;
+/// 启动后等token检查完再开始第一次同步
+const FIRST_SYNC_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+const SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6 * 3600);
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// 账单和充电过程重叠的时长(ms), 在误差内但不重叠时为负数, 超出误差时为None
+fn overlap(c: &HistoryCharge, b: &SuperchargerBill) -> Option<i64> {
+    let end = c.end_timestamp.max(c.start_timestamp);
+    let overlap = end.min(b.end_timestamp) - c.start_timestamp.max(b.start_timestamp);
+    (overlap >= -TOLERANCE).then_some(overlap)
+}
+
+/// 每张账单关联到重叠最多的一个充电过程, 每个充电过程最多关联一张账单, 返回有变化的充电过程
+/// 已关联到其他账单(不在bills中)的充电过程保持不变
+pub fn link(charges: &mut [HistoryCharge], bills: &[SuperchargerBill]) -> Vec<HistoryCharge> {
+    let in_bills = |id: i64| bills.iter().any(|b| b.session_id == id);
+    let mut linked = vec![false; charges.len()];
+    let mut changed = vec![false; charges.len()];
+    for b in bills.iter() {
+        let best = charges
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| {
+                !linked[*i]
+                    && (c.supercharger_session_id == 0 || in_bills(c.supercharger_session_id))
+            })
+            .filter_map(|(i, c)| Some((i, overlap(c, b)?)))
+            .max_by_key(|(_, overlap)| *overlap);
+        if let Some((i, _)) = best {
+            linked[i] = true;
+            let c = &mut charges[i];
+            if c.supercharger_session_id != b.session_id || c.cost != b.total_due {
+                c.supercharger_session_id = b.session_id;
+                c.cost = b.total_due;
+                c.currency = b.currency.clone();
+                changed[i] = true;
+            }
+        }
+    }
+    // 之前关联的账单已经关联到别的充电过程
+    for (i, c) in charges.iter_mut().enumerate() {
+        if !linked[i] && c.supercharger_session_id != 0 && in_bills(c.supercharger_session_id) {
+            c.supercharger_session_id = 0;
+            c.cost = 0.0;
+            c.currency.clear();
+            changed[i] = true;
+        }
+    }
+    charges
+        .iter()
+        .zip(changed)
+        .filter(|(_, changed)| *changed)
+        .map(|(c, _)| c.clone())
+        .collect()
+}
+
+/// 去掉已经关联到其他充电过程的账单, charges为这次要关联的
+pub fn unlinked(
+    bills: Vec<SuperchargerBill>,
+    stored: &[HistoryCharge],
+    charges: &[HistoryCharge],
+) -> Vec<SuperchargerBill> {
+    bills
+        .into_iter()
+        .filter(|b| {
+            !stored.iter().any(|s| {
+                s.supercharger_session_id == b.session_id
+                    && !charges
+                        .iter()
+                        .any(|c| c.start_timestamp == s.start_timestamp)
+            })
+        })
+        .collect()
+}
+
+/// 同步时间段内的账单, 不指定开始时间时从上次同步的最后一条账单开始, 最多一年
+pub async fn sync(
+    api: &ApiClient,
+    vehicle: &Vehicle,
+    conf: &AppConfig,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+) -> Result<usize, Error> {
+    let vid = vehicle.vehicle_id;
+    let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+    let start = match start {
+        Some(start) => start,
+        None => pika
+            .load_supercharger_bills(vid)
+            .await?
+            .last()
+            .and_then(|b| Utc.timestamp_millis_opt(b.start_timestamp).single())
+            .map(|t| t - Duration::days(1))
+            .unwrap_or_else(|| Utc::now() - Duration::days(365)),
+    };
+    let end = end.unwrap_or_else(Utc::now);
+    let bills: Vec<SuperchargerBill> = api
+        .charging_history_all(start, end)
+        .await?
+        .iter()
+        .filter(|r| r.vin == vehicle.vin)
+        .map(|r| r.to_bill())
+        .collect();
+    for b in bills.iter() {
+        pika.save_supercharger_bill(vid, b).await?;
+    }
+    let mut charges = pika.load_charges(vid).await?;
+    let changed = link(&mut charges, &bills);
+    for c in changed.iter() {
+        pika.save_charge(vid, c).await?;
+    }
+    info!(
+        "supercharger sync vin={} bills={} linked={}",
+        vehicle.vin,
+        bills.len(),
+        changed.len()
+    );
+    Ok(bills.len())
+}
+
+/// 同步账号下全部车辆的账单
+async fn sync_all(api: &ApiClient, conf: &AppConfig) -> Result<(), Error> {
+    for v in api.vehicles().await?.iter() {
+        sync(api, v, conf, None, None).await?;
+    }
+    Ok(())
+}
+
+/// 后台定期同步超充账单, 第一次同步要翻一年的账单, 不能阻塞车辆监控的启动
+pub fn spawn_sync(api: ApiClient, conf: ConfigReceiver, shutdown: ShutdownReceiver) {
+    tokio::spawn(async move {
+        let mut wait = FIRST_SYNC_DELAY;
+        loop {
+            tokio::select! {
+                _ = shutdown::wait(shutdown.clone()) => break,
+                _ = tokio::time::sleep(wait) => (),
+            }
+            let conf = conf.borrow().clone();
+            wait = match sync_all(&api, &conf).await {
+                Ok(()) => SYNC_INTERVAL,
+                Err(e) => {
+                    error!("supercharger::sync: {e}");
+                    RETRY_INTERVAL
+                }
+            };
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_by_overlap() {
+        let mut charges = vec![
+            HistoryCharge {
+                start_timestamp: 1_000_000,
+                end_timestamp: 3_000_000,
+                ..Default::default()
+            },
+            HistoryCharge {
+                start_timestamp: 100_000_000,
+                end_timestamp: 103_000_000,
+                ..Default::default()
+            },
+        ];
+        let bills = vec![SuperchargerBill {
+            session_id: 7,
+            start_timestamp: 1_500_000,
+            end_timestamp: 3_500_000,
+            total_due: 42.5,
+            currency: "CNY".into(),
+            ..Default::default()
+        }];
+        let changed = link(&mut charges, &bills);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(charges[0].supercharger_session_id, 7);
+        assert_eq!(charges[0].cost, 42.5);
+        assert_eq!(charges[1].supercharger_session_id, 0);
+        assert!(link(&mut charges, &bills).is_empty());
+    }
+
+    /// 前后两次充电都在账单的误差内, 账单只关联到重叠最多的那次
+    #[test]
+    fn link_back_to_back() {
+        let mut charges = vec![
+            HistoryCharge {
+                start_timestamp: 0,
+                end_timestamp: 1_800_000,
+                ..Default::default()
+            },
+            HistoryCharge {
+                start_timestamp: 2_100_000,
+                end_timestamp: 3_000_000,
+                ..Default::default()
+            },
+        ];
+        let bills = vec![SuperchargerBill {
+            session_id: 7,
+            start_timestamp: 60_000,
+            end_timestamp: 1_860_000,
+            total_due: 42.5,
+            ..Default::default()
+        }];
+        assert_eq!(link(&mut charges, &bills).len(), 1);
+        assert_eq!(charges[0].supercharger_session_id, 7);
+        assert_eq!(charges[1].supercharger_session_id, 0);
+        assert_eq!(charges[1].cost, 0.0);
+
+        // 监控只识别最后一次充电, 已关联到前一次的账单不再关联
+        let mut last = vec![charges[1].clone()];
+        let bills = unlinked(bills, &charges, &last);
+        assert!(bills.is_empty());
+        assert!(link(&mut last, &bills).is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+//! 车辆监控的守护任务, 监控任务异常退出时按退避时间重启, 并记录每辆车的运行状态供/api/health查询
+use crate::geocoder::Geocoder;
+use crate::metrics::metrics;
+use crate::mqtt::MqttPublisher;
+use crate::reload::ConfigReceiver;
+use crate::vehicle_monitor::VehicleMonitor;
+use crate::Error;
+use base::pb::tesla::Vehicle;
+use log::{error, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tesla_api::ApiClient;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// 运行超过该时长后再退出, 退避时间从头计算
+const STABLE_AFTER: Duration = Duration::from_secs(600);
+
+/// 单辆车的运行状态, 时间戳为毫秒
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct VehicleHealth {
+    pub vehicle_id: i64,
+    pub display_name: String,
+    /// 监控任务是否在运行, 等待重启时为false
+    pub running: bool,
+    /// connecting/connected/disconnected/closed
+    pub stream: String,
+    pub last_update: i64,
+    pub last_snapshot: i64,
+    pub restarts: u32,
+    pub last_error: String,
+}
+
+fn registry() -> &'static Mutex<BTreeMap<i64, VehicleHealth>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<i64, VehicleHealth>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// 修改车辆状态, 没有记录时新建
+pub fn update_health(vehicle_id: i64, f: impl FnOnce(&mut VehicleHealth)) {
+    let mut m = registry().lock().unwrap();
+    let h = m.entry(vehicle_id).or_insert_with(|| VehicleHealth {
+        vehicle_id,
+        ..Default::default()
+    });
+    f(h);
+}
+
+pub fn health() -> Vec<VehicleHealth> {
+    registry().lock().unwrap().values().cloned().collect()
+}
+
+fn panic_message(e: tokio::task::JoinError) -> String {
+    match e.try_into_panic() {
+        Ok(p) => p
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| p.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panic".into()),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// 第n次连续重启前等待的时间
+fn backoff(n: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(n.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// 守护中的车辆监控
+pub struct Supervised {
+    stop: tokio::sync::oneshot::Sender<()>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl Supervised {
+    /// 停止守护和监控, 等待未保存的数据写入
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.handle.await {
+            error!("supervisor: {e}");
+        }
+    }
+}
+
+pub fn supervise(
+    api: ApiClient,
+    vehicle: Vehicle,
+    conf: ConfigReceiver,
+    mqtt: Option<MqttPublisher>,
+    geocoder: Arc<Geocoder>,
+) -> Supervised {
+    let vehicle_id = vehicle.vehicle_id;
+    update_health(vehicle_id, |h| {
+        h.display_name = vehicle.display_name.clone()
+    });
+    spawn(vehicle_id, move || {
+        VehicleMonitor::init(
+            api.clone(),
+            vehicle.clone(),
+            conf.clone(),
+            mqtt.clone(),
+            Arc::clone(&geocoder),
+        )
+    })
+}
+
+/// 每次(重新)启动时调用start创建监控
+fn spawn<F, Fut>(vehicle_id: i64, mut start: F) -> Supervised
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<VehicleMonitor, Error>> + Send,
+{
+    let (stop, mut stop_receiver) = tokio::sync::oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        // 连续异常退出的次数
+        let mut failures = 0;
+        loop {
+            let started = tokio::time::Instant::now();
+            let result = start().await;
+            let error = match result {
+                Ok(mut vm) => {
+                    update_health(vehicle_id, |h| h.running = true);
+                    tokio::select! {
+                        _ = &mut stop_receiver => {
+                            vm.stop().await;
+                            break;
+                        }
+                        r = vm.join() => match r {
+                            Ok(()) => "monitor exited".to_string(),
+                            Err(e) => panic_message(e),
+                        },
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+            if started.elapsed() > STABLE_AFTER {
+                failures = 0;
+            }
+            failures += 1;
+            let wait = backoff(failures);
+            error!("vehicle monitor {vehicle_id} crashed: {error}, restart in {wait:?}");
+            metrics()
+                .monitor_restarts
+                .with_label_values(&[&vehicle_id.to_string()])
+                .inc();
+            update_health(vehicle_id, |h| {
+                h.running = false;
+                h.stream = "closed".into();
+                h.restarts += 1;
+                h.last_error = error;
+            });
+            tokio::select! {
+                _ = &mut stop_receiver => break,
+                _ = tokio::time::sleep(wait) => info!("restart vehicle monitor {vehicle_id}"),
+            }
+        }
+        // 车辆已移除或正在退出, 不再报告状态
+        registry().lock().unwrap().remove(&vehicle_id);
+    });
+    Supervised { stop, handle }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_max() {
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    /// 监控panic后按退避时间重启, /api/health返回重启次数和panic信息
+    #[tokio::test(start_paused = true)]
+    async fn restart_after_panic() {
+        let vehicle_id = 9001;
+        let starts = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let n = std::sync::Arc::clone(&starts);
+        let sv = spawn(vehicle_id, move || {
+            let first = n.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0;
+            async move {
+                Ok(VehicleMonitor::from_task(|exit| async move {
+                    if first {
+                        panic!("invalid frame");
+                    }
+                    let _ = exit.await;
+                }))
+            }
+        });
+        let vehicle = || async {
+            let (_, axum::Json(resp)) = crate::http::health().await;
+            let resp = serde_json::to_value(resp).unwrap();
+            resp["vehicles"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["vehicle_id"] == vehicle_id)
+                .cloned()
+        };
+        tokio::time::timeout(MIN_BACKOFF * 2, async {
+            while starts.load(std::sync::atomic::Ordering::SeqCst) < 2
+                || vehicle().await.is_some_and(|v| v["running"] == false)
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("restart");
+        let v = vehicle().await.unwrap();
+        assert_eq!(v["restarts"], 1);
+        assert_eq!(v["last_error"], "invalid frame");
+        assert_eq!(v["running"], true);
+        sv.stop().await;
+        assert!(vehicle().await.is_none());
+    }
+}
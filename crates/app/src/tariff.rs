@@ -0,0 +1,193 @@
+//! 充电费用计算
+use base::pb::base::*;
+use base::pb::tesla::*;
+use chrono::{Datelike, Local, TimeZone, Timelike, Weekday};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// 围栏对应的电价, 没有时使用默认电价
+pub fn find<'a>(tariffs: &'a [Tariff], geofence: &str) -> Option<&'a Tariff> {
+    tariffs
+        .iter()
+        .find(|t| !geofence.is_empty() && t.geofence == geofence)
+        .or_else(|| tariffs.iter().find(|t| t.geofence.is_empty()))
+}
+
+fn parse_minute(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    Some(h.trim().parse::<u32>().ok()? * 60 + m.trim().parse::<u32>().ok()?)
+}
+
+fn window_matches(w: &TariffWindow, ts: i64) -> bool {
+    let t = match Local.timestamp_millis_opt(ts).single() {
+        Some(t) => t,
+        None => return false,
+    };
+    let weekend = matches!(t.weekday(), Weekday::Sat | Weekday::Sun);
+    match w.days.as_str() {
+        "weekday" if weekend => return false,
+        "weekend" if !weekend => return false,
+        _ => (),
+    }
+    let (start, end) = match (parse_minute(&w.start), parse_minute(&w.end)) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return false,
+    };
+    let m = t.hour() * 60 + t.minute();
+    if start <= end {
+        start <= m && m < end
+    } else {
+        m >= start || m < end
+    }
+}
+
+/// 一段电量的费用, `before`为本次充电此前已充电量
+fn chunk_cost(t: &Tariff, ts: i64, before: f64, kwh: f64) -> f64 {
+    if !t.tiers.is_empty() {
+        let mut cost = 0.0;
+        let mut lower = 0.0;
+        for tier in t.tiers.iter() {
+            let upper = if tier.up_to_kwh > 0.0 {
+                tier.up_to_kwh
+            } else {
+                f64::MAX
+            };
+            let used = (before + kwh).min(upper) - before.max(lower);
+            if used > 0.0 {
+                cost += used * tier.price;
+            }
+            lower = upper;
+        }
+        return cost;
+    }
+    let price = t
+        .windows
+        .iter()
+        .find(|w| window_matches(w, ts))
+        .map(|w| w.price)
+        .unwrap_or(t.price);
+    kwh * price
+}
+
+/// 按充电过程中的charge_energy_added增量逐段计价
+pub fn cost(t: &Tariff, charge: &HistoryCharge) -> f64 {
+    if charge.details.is_empty() {
+        return chunk_cost(t, charge.start_timestamp, 0.0, charge.charge_energy_added);
+    }
+    let mut total = 0.0;
+    let mut before = 0.0;
+    for cs in charge.details.iter() {
+        let kwh = cs.charge_energy_added - before;
+        if kwh > 0.0 {
+            total += chunk_cost(t, cs.timestamp, before, kwh);
+            before = cs.charge_energy_added;
+        }
+    }
+    total
+}
+
+/// 按围栏电价计算并填充费用, 已有费用(如超充账单)的不覆盖
+pub fn apply(tariffs: &[Tariff], charge: &mut HistoryCharge) {
+    if charge.cost > 0.0 {
+        return;
+    }
+    if let Some(t) = find(tariffs, &charge.geofence) {
+        charge.cost = cost(t, charge);
+        charge.currency = t.currency.clone();
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MonthlyCost {
+    pub month: i32,
+    pub currency: String,
+    pub sessions: i32,
+    pub charge_energy_added: f64,
+    pub cost: f64,
+}
+
+/// 按月(本地时间)和币种汇总
+pub fn monthly_summary(charges: &[HistoryCharge]) -> Vec<MonthlyCost> {
+    let mut m: BTreeMap<(i32, String), MonthlyCost> = BTreeMap::new();
+    for c in charges.iter() {
+        let month = match Local.timestamp_millis_opt(c.start_timestamp).single() {
+            Some(t) => t.year() * 100 + t.month() as i32,
+            None => continue,
+        };
+        let s = m
+            .entry((month, c.currency.clone()))
+            .or_insert_with(|| MonthlyCost {
+                month,
+                currency: c.currency.clone(),
+                ..Default::default()
+            });
+        s.sessions += 1;
+        s.charge_energy_added += c.charge_energy_added;
+        s.cost += c.cost;
+    }
+    m.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn charge(points: &[(i64, f64)]) -> HistoryCharge {
+        HistoryCharge {
+            start_timestamp: points[0].0,
+            charge_energy_added: points[points.len() - 1].1,
+            details: points
+                .iter()
+                .map(|(timestamp, e)| VehicleChargeState {
+                    timestamp: *timestamp,
+                    charge_energy_added: *e,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tiered() {
+        let t = Tariff {
+            tiers: vec![
+                TariffTier {
+                    up_to_kwh: 10.0,
+                    price: 1.0,
+                },
+                TariffTier {
+                    up_to_kwh: 0.0,
+                    price: 2.0,
+                },
+            ],
+            ..Default::default()
+        };
+        let c = charge(&[(0, 4.0), (1, 12.0), (2, 15.0)]);
+        assert!((cost(&t, &c) - (10.0 + 5.0 * 2.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_of_use() {
+        let night = Local
+            .with_ymd_and_hms(2023, 3, 1, 23, 30, 0)
+            .unwrap()
+            .timestamp_millis();
+        let day = Local
+            .with_ymd_and_hms(2023, 3, 2, 12, 0, 0)
+            .unwrap()
+            .timestamp_millis();
+        let t = Tariff {
+            price: 1.0,
+            windows: vec![TariffWindow {
+                days: "weekday".into(),
+                start: "22:00".into(),
+                end: "06:00".into(),
+                price: 0.3,
+            }],
+            ..Default::default()
+        };
+        let c = charge(&[(night, 10.0), (day, 20.0)]);
+        assert!((cost(&t, &c) - (10.0 * 0.3 + 10.0 * 1.0)).abs() < 1e-9);
+    }
+}
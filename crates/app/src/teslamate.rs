@@ -0,0 +1,458 @@
+//! TeslaMate 数据导入
+//!
+//! 支持两种输入:
+//! * `pg_dump` 导出的sql文件(COPY ... FROM stdin 格式)
+//! * `\copy ... to 'xxx.csv' csv header` 导出的目录, 每张表一个`<table>.csv`
+//!
+//! positions/charges/states 转为 `VehiclePeriodRecord`, drives 转为 `Trip`,
+//! charging_processes 转为 `HistoryCharge`. TeslaMate使用公里, 转换为与stream一致的英里.
+use crate::Error;
+use base::pb::base::AppConfig;
+use base::pb::tesla::*;
+use chrono::NaiveDateTime;
+use db::pika::PikaConnection;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+const KM_PER_MILE: f64 = 1.609344;
+
+/// 导出的一张表
+#[derive(Debug, Default)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl Table {
+    pub fn records(&self) -> impl Iterator<Item = Row<'_>> {
+        self.rows.iter().map(move |values| Row {
+            table: self,
+            values,
+        })
+    }
+}
+
+pub struct Row<'a> {
+    table: &'a Table,
+    values: &'a [Option<String>],
+}
+
+impl<'a> Row<'a> {
+    pub fn str(&self, name: &str) -> Option<&'a str> {
+        let i = self.table.columns.iter().position(|c| c == name)?;
+        self.values.get(i)?.as_deref()
+    }
+
+    pub fn i64(&self, name: &str) -> Option<i64> {
+        self.str(name)?.parse().ok()
+    }
+
+    pub fn f64(&self, name: &str) -> f64 {
+        self.str(name).and_then(|s| s.parse().ok()).unwrap_or(0.0)
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        matches!(self.str(name), Some("t" | "true"))
+    }
+
+    /// TeslaMate时间均为UTC, 返回毫秒
+    pub fn timestamp(&self, name: &str) -> Option<i64> {
+        let s = self.str(name)?;
+        let s = s.trim_end_matches('Z');
+        ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+            .iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+            .map(|t| t.and_utc().timestamp_millis())
+    }
+}
+
+/// 解析pg_dump输出中的 COPY 数据块
+pub fn parse_pg_dump(text: &str) -> HashMap<String, Table> {
+    let mut tables = HashMap::new();
+    let mut current: Option<(String, Table)> = None;
+    for line in text.lines() {
+        if let Some((_, table)) = current.as_mut() {
+            if line == "\\." {
+                let (name, table) = current.take().unwrap();
+                tables.insert(name, table);
+            } else {
+                table
+                    .rows
+                    .push(line.split('\t').map(unescape_copy_value).collect());
+            }
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("COPY ") {
+            let (name, rest) = match rest.split_once(" (") {
+                Some(v) => v,
+                None => continue,
+            };
+            let name = name.rsplit('.').next().unwrap_or(name).to_string();
+            let columns = rest
+                .split(") FROM stdin")
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(|c| c.trim().trim_matches('"').to_string())
+                .collect();
+            current = Some((
+                name,
+                Table {
+                    columns,
+                    rows: vec![],
+                },
+            ));
+        }
+    }
+    tables
+}
+
+fn unescape_copy_value(v: &str) -> Option<String> {
+    if v == "\\N" {
+        return None;
+    }
+    let mut s = String::with_capacity(v.len());
+    let mut chars = v.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => s.push('\t'),
+            Some('n') => s.push('\n'),
+            Some('r') => s.push('\r'),
+            Some(c) => s.push(c),
+            None => (),
+        }
+    }
+    Some(s)
+}
+
+/// 读取目录下的 `<table>.csv`
+pub fn load_csv_dir(dir: &Path) -> Result<HashMap<String, Table>, Error> {
+    let mut tables = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("csv") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let mut reader = csv::Reader::from_path(&path)?;
+        let mut table = Table {
+            columns: reader.headers()?.iter().map(|h| h.to_string()).collect(),
+            rows: vec![],
+        };
+        for record in reader.records() {
+            let record = record?;
+            table.rows.push(
+                record
+                    .iter()
+                    .map(|v| (!v.is_empty()).then(|| v.to_string()))
+                    .collect(),
+            );
+        }
+        tables.insert(name, table);
+    }
+    Ok(tables)
+}
+
+pub fn load(path: &str) -> Result<HashMap<String, Table>, Error> {
+    let p = Path::new(path);
+    if p.is_dir() {
+        load_csv_dir(p)
+    } else {
+        Ok(parse_pg_dump(&std::fs::read_to_string(p)?))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportResult {
+    pub records: BTreeMap<(i64, i64), VehiclePeriodRecord>,
+    pub trips: Vec<(i64, Trip)>,
+    pub charges: Vec<(i64, HistoryCharge)>,
+}
+
+/// 把TeslaMate的表转换为本项目的数据结构, `vehicle_id`用于覆盖cars表中的vid
+pub fn convert(tables: &HashMap<String, Table>, vehicle_id: Option<i64>) -> ImportResult {
+    let empty = Table::default();
+    let table = |name: &str| tables.get(name).unwrap_or(&empty);
+    let cars: HashMap<i64, i64> = table("cars")
+        .records()
+        .filter_map(|r| Some((r.i64("id")?, r.i64("vid")?)))
+        .collect();
+    let vid_of = |r: &Row| match vehicle_id {
+        Some(vid) => Some(vid),
+        None => r.i64("car_id").and_then(|c| cars.get(&c).cloned()),
+    };
+
+    let mut result = ImportResult::default();
+    let mut positions: HashMap<i64, Row> = HashMap::new();
+    let mut tracks: HashMap<i64, Vec<TripSnapshot>> = HashMap::new();
+    for r in table("positions").records() {
+        let (Some(vid), Some(ts)) = (vid_of(&r), r.timestamp("date")) else {
+            continue;
+        };
+        let update = DrivingState {
+            timestamp: ts,
+            speed: r.f64("speed") / KM_PER_MILE,
+            odometer: r.f64("odometer") / KM_PER_MILE,
+            soc: r.f64("battery_level"),
+            elevation: r.f64("elevation"),
+            est_lat: r.f64("latitude"),
+            est_lng: r.f64("longitude"),
+            power: r.f64("power"),
+            range: r.f64("ideal_battery_range_km") / KM_PER_MILE,
+            est_range: r.f64("est_battery_range_km") / KM_PER_MILE,
+            ..Default::default()
+        };
+        if let Some(drive_id) = r.i64("drive_id") {
+            tracks.entry(drive_id).or_default().push(TripSnapshot {
+                timestamp: ts,
+                longitude: update.est_lng,
+                latitude: update.est_lat,
+                elevation: update.elevation,
+                inside_temperature: r.f64("inside_temp"),
+                outside_temperature: r.f64("outside_temp"),
+            });
+        }
+        let pr = period_record(&mut result.records, vid, ts);
+        let snapshot = pr.snapshot.get_or_insert_with(Default::default);
+        snapshot.climate_state = Some(VehicleClimateState {
+            inside_temp: r.f64("inside_temp"),
+            outside_temp: r.f64("outside_temp"),
+            timestamp: ts,
+            ..Default::default()
+        });
+        pr.updates.push(update);
+        if let Some(id) = r.i64("id") {
+            positions.insert(id, r);
+        }
+    }
+
+    let mut details: HashMap<i64, Vec<VehicleChargeState>> = HashMap::new();
+    for r in table("charges").records() {
+        let (Some(vid), Some(ts)) = (vid_of(&r), r.timestamp("date")) else {
+            continue;
+        };
+        let cs = VehicleChargeState {
+            timestamp: ts,
+            charging_state: "Charging".to_string(),
+            battery_heater_on: r.bool("battery_heater_on"),
+            battery_level: r.f64("battery_level"),
+            usable_battery_level: r.f64("usable_battery_level"),
+            charge_energy_added: r.f64("charge_energy_added"),
+            charger_actual_current: r.f64("charger_actual_current"),
+            charger_power: r.f64("charger_power"),
+            charger_voltage: r.f64("charger_voltage"),
+            ideal_battery_range: r.f64("ideal_battery_range_km") / KM_PER_MILE,
+            battery_range: r.f64("rated_battery_range_km") / KM_PER_MILE,
+            ..Default::default()
+        };
+        if let Some(cp) = r.i64("charging_process_id") {
+            details.entry(cp).or_default().push(cs.clone());
+        }
+        let pr = period_record(&mut result.records, vid, ts);
+        let snapshot = pr.snapshot.get_or_insert_with(Default::default);
+        let climate = snapshot.climate_state.get_or_insert_with(Default::default);
+        climate.outside_temp = r.f64("outside_temp");
+        snapshot.charge_state = Some(cs);
+    }
+
+    // states: online/asleep/offline, 状态开始时补一条记录, 其余记录按时间区间标注状态
+    let mut states: HashMap<i64, Vec<(i64, i64, String)>> = HashMap::new();
+    for r in table("states").records() {
+        let (Some(vid), Some(start), Some(state)) =
+            (vid_of(&r), r.timestamp("start_date"), r.str("state"))
+        else {
+            continue;
+        };
+        let end = r.timestamp("end_date").unwrap_or(i64::MAX);
+        period_record(&mut result.records, vid, start);
+        states
+            .entry(vid)
+            .or_default()
+            .push((start, end, state.to_string()));
+    }
+    for ((vid, minute), pr) in result.records.iter_mut() {
+        let snapshot = pr.snapshot.get_or_insert_with(Default::default);
+        snapshot.vehicle_id = *vid;
+        let ts = minute * 1000 + 59_999;
+        snapshot.state = states
+            .get(vid)
+            .and_then(|v| v.iter().find(|(s, e, _)| *s <= ts && ts < *e))
+            .map(|(_, _, s)| s.clone())
+            .unwrap_or_else(|| {
+                if pr.updates.is_empty() {
+                    "offline".to_string()
+                } else {
+                    "online".to_string()
+                }
+            });
+    }
+
+    let addresses: HashMap<i64, String> = table("addresses")
+        .records()
+        .filter_map(|r| {
+            let name = r.str("name").or(r.str("display_name"))?;
+            Some((r.i64("id")?, name.to_string()))
+        })
+        .collect();
+    let address = |r: &Row, col: &str| {
+        r.i64(col)
+            .and_then(|id| addresses.get(&id).cloned())
+            .unwrap_or_default()
+    };
+    let position = |r: &Row, col: &str| r.i64(col).and_then(|id| positions.get(&id));
+
+    for r in table("drives").records() {
+        let (Some(vid), Some(start)) = (vid_of(&r), r.timestamp("start_date")) else {
+            continue;
+        };
+        let mut track = r
+            .i64("id")
+            .and_then(|id| tracks.remove(&id))
+            .unwrap_or_default();
+        track.sort_by_key(|s| s.timestamp);
+        result.trips.push((
+            vid,
+            Trip {
+                timestamp: start,
+                end_timestamp: r.timestamp("end_date").unwrap_or_default(),
+                start_address: address(&r, "start_address_id"),
+                finish_address: address(&r, "end_address_id"),
+                distance: r.f64("distance") / KM_PER_MILE,
+                start_battery_level: position(&r, "start_position_id")
+                    .map(|p| p.f64("battery_level"))
+                    .unwrap_or_default(),
+                end_battery_level: position(&r, "end_position_id")
+                    .map(|p| p.f64("battery_level"))
+                    .unwrap_or_default(),
+                track,
+                ..Default::default()
+            },
+        ));
+    }
+
+    for r in table("charging_processes").records() {
+        let (Some(vid), Some(start)) = (vid_of(&r), r.timestamp("start_date")) else {
+            continue;
+        };
+        let mut details = r
+            .i64("id")
+            .and_then(|id| details.remove(&id))
+            .unwrap_or_default();
+        details.sort_by_key(|d| d.timestamp);
+        let pos = position(&r, "position_id");
+        result.charges.push((
+            vid,
+            HistoryCharge {
+                start_timestamp: start,
+                end_timestamp: r.timestamp("end_date").unwrap_or_default(),
+                charge_energy_added: r.f64("charge_energy_added"),
+                start_battery_level: r.f64("start_battery_level"),
+                end_battery_level: r.f64("end_battery_level"),
+                latitude: pos.map(|p| p.f64("latitude")).unwrap_or_default(),
+                longitude: pos.map(|p| p.f64("longitude")).unwrap_or_default(),
+                details,
+                ..Default::default()
+            },
+        ));
+    }
+    result
+}
+
+/// 按分钟归并记录, 和VehicleMonitor保存的粒度一致
+fn period_record(
+    records: &mut BTreeMap<(i64, i64), VehiclePeriodRecord>,
+    vid: i64,
+    ts: i64,
+) -> &mut VehiclePeriodRecord {
+    let minute = ts / 1000 / 60 * 60;
+    records
+        .entry((vid, minute))
+        .or_insert_with(|| VehiclePeriodRecord {
+            timestamp: minute,
+            ..Default::default()
+        })
+}
+
+/// 导入到pika
+pub async fn import(path: &str, vehicle_id: Option<i64>, conf: &AppConfig) -> Result<(), Error> {
+    let tables = load(path)?;
+    info!(
+        "teslamate tables: {:?}",
+        tables
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.rows.len()))
+            .collect::<Vec<_>>()
+    );
+    if vehicle_id.is_none() && !tables.contains_key("cars") {
+        warn!("no cars table found and --vehicle-id not set, nothing will be imported");
+    }
+    let result = convert(&tables, vehicle_id);
+    let mut pika = PikaConnection::shared(&conf.pika_address).await?;
+    for ((vid, _), pr) in result.records.iter() {
+        pika.save_vehicle_period_record(*vid, pr).await?;
+    }
+    for (vid, trip) in result.trips.iter() {
+        pika.save_trip(*vid, trip).await?;
+    }
+    for (vid, charge) in result.charges.iter() {
+        pika.save_charge(*vid, charge).await?;
+    }
+    info!(
+        "teslamate import done, records={} trips={} charges={}",
+        result.records.len(),
+        result.trips.len(),
+        result.charges.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "\
+COPY public.cars (id, eid, vid, name) FROM stdin;
+1\t100\t200\tmy car
+\\.
+COPY public.positions (id, date, latitude, longitude, speed, odometer, battery_level, drive_id, car_id) FROM stdin;
+1\t2023-02-01 10:00:05.123\t31.2\t121.4\t16.09344\t1609.344\t80\t7\t1
+2\t2023-02-01 10:00:35\t31.3\t121.5\t\\N\t1610.953344\t79\t7\t1
+\\.
+COPY public.drives (id, start_date, end_date, distance, start_position_id, end_position_id, car_id) FROM stdin;
+7\t2023-02-01 10:00:05\t2023-02-01 10:00:35\t1.609344\t1\t2\t1
+\\.
+";
+
+    #[test]
+    fn parse_dump() {
+        let tables = parse_pg_dump(DUMP);
+        assert_eq!(tables["positions"].rows.len(), 2);
+        assert_eq!(tables["positions"].rows[1][4], None);
+        assert_eq!(tables["cars"].columns, vec!["id", "eid", "vid", "name"]);
+    }
+
+    #[test]
+    fn convert_dump() {
+        let result = convert(&parse_pg_dump(DUMP), None);
+        assert_eq!(result.records.len(), 1);
+        let pr = &result.records[&(200, 1675245600)];
+        assert_eq!(pr.updates.len(), 2);
+        assert!((pr.updates[0].speed - 10.0).abs() < 1e-9);
+        assert!((pr.updates[0].odometer - 1000.0).abs() < 1e-9);
+        assert_eq!(pr.snapshot.as_ref().unwrap().state, "online");
+        let (vid, trip) = &result.trips[0];
+        assert_eq!(*vid, 200);
+        assert_eq!(trip.track.len(), 2);
+        assert_eq!(trip.start_battery_level, 80.0);
+        assert_eq!(trip.end_battery_level, 79.0);
+        assert!((trip.distance - 1.0).abs() < 1e-9);
+    }
+}
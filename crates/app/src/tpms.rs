@@ -0,0 +1,257 @@
+//! 胎压历史和慢漏气检测
+use base::pb::tesla::*;
+use chrono::{NaiveDate, TimeZone};
+use serde::Serialize;
+
+/// 换算到该温度(℃)下的胎压
+const REFERENCE_TEMP: f64 = 20.0;
+const ATMOSPHERE: f64 = 1.01325;
+/// 检测最近多少天
+const LEAK_WINDOW: usize = 14;
+const LEAK_MIN_DAYS: usize = 4;
+/// 相对其他轮胎每天下降超过该值(bar)
+const LEAK_SLOPE: f64 = -0.01;
+/// 且窗口内累计下降超过该值(bar)
+const LEAK_DROP: f64 = 0.1;
+
+const WHEELS: [&str; 4] = ["fl", "fr", "rl", "rr"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowLeak {
+    pub wheel: String,
+    /// bar/天
+    pub slope: f64,
+    pub drop: f64,
+}
+
+/// 按理想气体定律把表压换算到参考温度
+pub fn normalise(pressure: f64, outside_temp: f64) -> f64 {
+    (pressure + ATMOSPHERE) * (REFERENCE_TEMP + 273.15) / (outside_temp + 273.15) - ATMOSPHERE
+}
+
+fn wheels(p: &TpmsPressure) -> [f64; 4] {
+    [p.fl, p.fr, p.rl, p.rr]
+}
+
+/// 快照中没有胎压(如休眠)时返回None
+pub fn sample(timestamp: i64, d: &VehicleData) -> Option<TpmsPressure> {
+    let vs = d.vehicle_state.as_ref()?;
+    let pressures = [
+        vs.tpms_pressure_fl,
+        vs.tpms_pressure_fr,
+        vs.tpms_pressure_rl,
+        vs.tpms_pressure_rr,
+    ];
+    if pressures.iter().any(|p| *p <= 0.0) {
+        return None;
+    }
+    let outside_temp = d
+        .climate_state
+        .as_ref()
+        .map(|cs| cs.outside_temp)
+        .unwrap_or(REFERENCE_TEMP);
+    let [fl, fr, rl, rr] = pressures.map(|p| normalise(p, outside_temp));
+    Some(TpmsPressure {
+        timestamp,
+        fl,
+        fr,
+        rl,
+        rr,
+        outside_temp,
+    })
+}
+
+fn median(mut v: Vec<f64>) -> f64 {
+    if v.is_empty() {
+        return 0.0;
+    }
+    v.sort_by(|a, b| a.total_cmp(b));
+    v[v.len() / 2]
+}
+
+pub fn daily(day: i32, samples: &[TpmsPressure]) -> TpmsDaily {
+    let m = |i: usize| median(samples.iter().map(|p| wheels(p)[i]).collect());
+    TpmsDaily {
+        day,
+        pressure: Some(TpmsPressure {
+            timestamp: samples.last().map(|p| p.timestamp).unwrap_or_default(),
+            fl: m(0),
+            fr: m(1),
+            rl: m(2),
+            rr: m(3),
+            outside_temp: median(samples.iter().map(|p| p.outside_temp).collect()),
+        }),
+        samples: samples.len() as i32,
+    }
+}
+
+/// 本地日期yyyymmdd, timestamp为毫秒
+pub fn local_day(timestamp: i64) -> i32 {
+    chrono::Local
+        .timestamp_millis_opt(timestamp)
+        .single()
+        .map(|t| t.format("%Y%m%d").to_string().parse().unwrap_or_default())
+        .unwrap_or_default()
+}
+
+/// 累计当天的胎压, 每天检测一次慢漏气
+#[derive(Default)]
+pub struct TpmsTracker {
+    day: i32,
+    samples: Vec<TpmsPressure>,
+    dirty: bool,
+    checked_day: i32,
+}
+
+impl TpmsTracker {
+    /// 重启后用已保存的快照恢复当天的数据, 避免只用重启后的数据覆盖当天的中位数. records需按时间排序
+    pub fn from_records(records: &[VehiclePeriodRecord]) -> Self {
+        let mut t = Self::default();
+        for pr in records.iter() {
+            let timestamp = pr.timestamp * 1000;
+            if let Some(s) = pr.snapshot.as_ref().and_then(|d| sample(timestamp, d)) {
+                t.add(local_day(timestamp), s);
+            }
+        }
+        t.dirty = false;
+        t
+    }
+
+    pub fn add(&mut self, day: i32, sample: TpmsPressure) {
+        if day != self.day {
+            self.day = day;
+            self.samples.clear();
+        }
+        self.samples.push(sample);
+        self.dirty = true;
+    }
+
+    /// 有新数据时返回当天的中位数
+    pub fn take_daily(&mut self) -> Option<TpmsDaily> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(daily(self.day, &self.samples))
+    }
+
+    pub fn needs_check(&mut self) -> bool {
+        std::mem::replace(&mut self.checked_day, self.day) != self.day
+    }
+}
+
+/// 最小二乘斜率
+fn slope(points: &[(f64, f64)]) -> f64 {
+    let n = points.len() as f64;
+    let mx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxy: f64 = points.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum();
+    let sxx: f64 = points.iter().map(|p| (p.0 - mx).powi(2)).sum();
+    if sxx == 0.0 {
+        0.0
+    } else {
+        sxy / sxx
+    }
+}
+
+/// 某个轮胎相对其他轮胎的压力持续下降, 排除了温度和整体变化的影响. days需按日期排序
+pub fn slow_leaks(days: &[TpmsDaily]) -> Vec<SlowLeak> {
+    let days: Vec<_> = days
+        .iter()
+        .filter_map(|d| {
+            let date = NaiveDate::parse_from_str(&d.day.to_string(), "%Y%m%d").ok()?;
+            Some((date, wheels(d.pressure.as_ref()?)))
+        })
+        .collect();
+    let days = &days[days.len().saturating_sub(LEAK_WINDOW)..];
+    if days.len() < LEAK_MIN_DAYS {
+        return vec![];
+    }
+    let first = days[0].0;
+    let mut v = vec![];
+    for (i, wheel) in WHEELS.iter().enumerate() {
+        let points: Vec<_> = days
+            .iter()
+            .map(|(date, p)| {
+                let others = (p.iter().sum::<f64>() - p[i]) / 3.0;
+                ((*date - first).num_days() as f64, p[i] - others)
+            })
+            .collect();
+        let slope = slope(&points);
+        let span = points[points.len() - 1].0 - points[0].0;
+        let drop = -slope * span;
+        if slope <= LEAK_SLOPE && drop >= LEAK_DROP {
+            v.push(SlowLeak {
+                wheel: wheel.to_string(),
+                slope,
+                drop,
+            });
+        }
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_temperature() {
+        assert!((normalise(2.9, REFERENCE_TEMP) - 2.9).abs() < 1e-9);
+        assert!(normalise(2.7, 0.0) > 2.9);
+    }
+
+    #[test]
+    fn detect_slow_leak() {
+        let days: Vec<_> = (0..10)
+            .map(|i| TpmsDaily {
+                day: 20240101 + i,
+                pressure: Some(TpmsPressure {
+                    // 整体随天气波动, 左后每天漏0.03
+                    fl: 2.9 + i as f64 * 0.01,
+                    fr: 2.9 + i as f64 * 0.01,
+                    rl: 2.9 - i as f64 * 0.02,
+                    rr: 2.9 + i as f64 * 0.01,
+                    ..Default::default()
+                }),
+                samples: 60,
+            })
+            .collect();
+        let leaks = slow_leaks(&days);
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].wheel, "rl");
+        assert!(slow_leaks(&days[..3]).is_empty());
+    }
+
+    #[test]
+    fn restore_after_restart() {
+        let base = 1704074400; // 2024-01-01 10:00 +08:00
+        let records: Vec<_> = (0..5)
+            .map(|i| VehiclePeriodRecord {
+                timestamp: base + i * 600,
+                snapshot: Some(VehicleData {
+                    vehicle_state: Some(VehicleState {
+                        tpms_pressure_fl: 2.9 + i as f64 * 0.1,
+                        tpms_pressure_fr: 2.9,
+                        tpms_pressure_rl: 2.9,
+                        tpms_pressure_rr: 2.9,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect();
+        let mut t = TpmsTracker::from_records(&records[..4]);
+        assert!(t.take_daily().is_none());
+        let pr = &records[4];
+        let now = pr.timestamp * 1000;
+        t.add(
+            local_day(now),
+            sample(now, pr.snapshot.as_ref().unwrap()).unwrap(),
+        );
+        let d = t.take_daily().unwrap();
+        assert_eq!(d.samples, 5);
+        assert!((d.pressure.unwrap().fl - 3.1).abs() < 1e-9);
+    }
+}
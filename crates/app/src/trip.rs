@@ -0,0 +1,106 @@
+//! 从区间数据中识别行程
+use crate::geocoder::Geocoder;
+use crate::geofence;
+use base::pb::{base::Geofence, tesla::*};
+
+/// records需按时间排序, 车辆在线期间的推送为一次行程
+pub fn detect_trips(records: &[VehiclePeriodRecord]) -> Vec<Trip> {
+    let mut trips = vec![];
+    let mut one_trip = Trip::default();
+    for pr in records.iter() {
+        let snapshot = match pr.snapshot.as_ref() {
+            Some(s) => s,
+            None => continue,
+        };
+        if snapshot.state == "online" {
+            let mut s = TripSnapshot::default();
+            for ds in pr.updates.iter() {
+                s.timestamp = base::timestamp_ms(ds.timestamp);
+                s.latitude = ds.est_lat;
+                s.longitude = ds.est_lng;
+                s.elevation = ds.elevation;
+                if let Some(cs) = &snapshot.climate_state {
+                    s.inside_temperature = cs.inside_temp;
+                    s.outside_temperature = cs.outside_temp;
+                }
+            }
+            if s.timestamp > 0 {
+                one_trip.track.push(s);
+            }
+        } else if !one_trip.track.is_empty() {
+            trips.push(std::mem::take(&mut one_trip));
+        }
+    }
+    trips
+}
+
+/// 实时识别行程用的区间数据, 去掉已经结束的行程, 保留最后一个不在线的快照及之后的数据
+pub fn trim_window(records: &mut Vec<VehiclePeriodRecord>) {
+    if let Some(i) = records
+        .iter()
+        .rposition(|pr| pr.snapshot.as_ref().is_some_and(|s| s.state != "online"))
+    {
+        records.drain(..i);
+    }
+}
+
+/// 补全起止时间/围栏/地址, 地址优先使用已保存的结果
+pub async fn fill(trip: &mut Trip, stored: &[Trip], geofences: &[Geofence], geocoder: &Geocoder) {
+    let (first, last) = (&trip.track[0], &trip.track[trip.track.len() - 1]);
+    trip.timestamp = first.timestamp;
+    trip.end_timestamp = last.timestamp;
+    trip.start_geofence = geofence::name_of(geofences, first.latitude, first.longitude);
+    trip.finish_geofence = geofence::name_of(geofences, last.latitude, last.longitude);
+    let stored = stored.iter().find(|t| t.timestamp == trip.timestamp);
+    match stored {
+        Some(t) if !t.start_address.is_empty() && !t.finish_address.is_empty() => {
+            trip.start_address = t.start_address.clone();
+            trip.finish_address = t.finish_address.clone();
+        }
+        _ => {
+            trip.start_address = geocoder.address(first.latitude, first.longitude).await;
+            trip.finish_address = geocoder.address(last.latitude, last.longitude).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, state: &str) -> VehiclePeriodRecord {
+        VehiclePeriodRecord {
+            timestamp,
+            updates: vec![DrivingState {
+                timestamp: timestamp * 1000,
+                est_lat: 31.2304,
+                est_lng: 121.4737,
+                ..Default::default()
+            }],
+            snapshot: Some(VehicleData {
+                state: state.into(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// 结束的行程识别一次后从窗口中去掉, 还在进行的保留
+    #[test]
+    fn window_drops_finished_trip() {
+        let mut window = vec![];
+        for (i, state) in ["asleep", "online", "online", "offline", "online"]
+            .iter()
+            .enumerate()
+        {
+            window.push(record(1700000000 + i as i64 * 60, state));
+        }
+        let trips = detect_trips(&window);
+        assert_eq!(trips.len(), 1);
+        assert_eq!(trips[0].track.len(), 2);
+        trim_window(&mut window);
+        assert_eq!(window.len(), 2);
+        assert!(detect_trips(&window).is_empty());
+        window.push(record(1700000300, "asleep"));
+        assert_eq!(detect_trips(&window).len(), 1);
+    }
+}
@@ -1,105 +1,468 @@
+use crate::events::EventExtractor;
+use crate::geocoder::Geocoder;
+use crate::geofence::{self, GeofenceTracker};
+use crate::grpc;
+use crate::metrics::metrics;
+use crate::mqtt::MqttPublisher;
+use crate::notify::{Notifier, RuleEngine};
+use crate::reload::ConfigReceiver;
+use crate::software_update::SoftwareUpdateTracker;
+use crate::supervisor::update_health;
+use crate::tpms::{self, TpmsTracker};
 use crate::Error;
+use crate::{battery, charging, supercharger, trip};
 use base::pb::{base::*, tesla::*};
 use db::pika::*;
 use futures_util::StreamExt;
-use itertools::Itertools;
 use log::{error, info};
 use std::sync::Arc;
 use tesla_api::{ApiClient, StreamMessage};
 
 pub struct VehicleMonitor {
     pub exit_sender: tokio::sync::oneshot::Sender<String>,
+    handle: tokio::task::JoinHandle<()>,
 }
 
 use async_stream::stream;
 use futures_util::pin_mut;
 
+/// 单辆车的处理状态, 实时监控和回放共用
+pub struct MonitorState {
+    vehicle_id: i64,
+    conf: AppConfig,
+    mqtt: Option<MqttPublisher>,
+    pr: VehiclePeriodRecord,
+    /// 最后一次充电过程的区间数据, 见charging::trim_window
+    charge_window: Vec<VehiclePeriodRecord>,
+    /// 还没结束的行程的区间数据, 见trip::trim_window
+    trip_window: Vec<VehiclePeriodRecord>,
+    geocoder: Arc<Geocoder>,
+    geofences: Vec<Geofence>,
+    geofence_tracker: GeofenceTracker,
+    geofence_events: Vec<GeofenceEvent>,
+    notifier: Notifier,
+    rules: RuleEngine,
+    software_updates: Vec<SoftwareUpdateRecord>,
+    software_update_tracker: SoftwareUpdateTracker,
+    /// 已保存的当前软件版本
+    software_version: String,
+    tpms_tracker: TpmsTracker,
+    event_extractor: EventExtractor,
+    vehicle_events: Vec<VehicleEvent>,
+}
+
+impl MonitorState {
+    pub async fn new(
+        vehicle_id: i64,
+        conf: AppConfig,
+        mqtt: Option<MqttPublisher>,
+        geocoder: Arc<Geocoder>,
+    ) -> Self {
+        let notify_conf = conf.notify.clone().unwrap_or_default();
+        let mut pika = PikaConnection::shared(&conf.pika_address)
+            .await
+            .map_err(|e| error!("PikaConnection::shared: {e}"))
+            .ok();
+        let software_version = match &mut pika {
+            Some(pika) => load_software_version(pika, vehicle_id).await,
+            None => String::new(),
+        };
+        // 第一次推送前就需要围栏, 否则已在围栏内会被当作进入
+        let geofences = match &mut pika {
+            Some(pika) => geofence::load_all(&conf, pika)
+                .await
+                .map_err(|e| error!("geofence::load_all: {e}"))
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        let recent = match &mut pika {
+            Some(pika) => load_recent_records(pika, vehicle_id).await,
+            None => vec![],
+        };
+        let mut charge_window: Vec<_> = recent.iter().map(charging::window_record).collect();
+        charging::trim_window(&mut charge_window);
+        let mut trip_window: Vec<_> = recent.iter().map(charging::window_record).collect();
+        trip::trim_window(&mut trip_window);
+        Self {
+            vehicle_id,
+            mqtt,
+            pr: VehiclePeriodRecord::default(),
+            charge_window,
+            trip_window,
+            geocoder,
+            geofences,
+            geofence_tracker: GeofenceTracker::default(),
+            geofence_events: vec![],
+            notifier: Notifier::new(&notify_conf, &conf.api_config.clone().unwrap_or_default()),
+            rules: RuleEngine::new(vehicle_id, &notify_conf),
+            software_updates: vec![],
+            software_update_tracker: SoftwareUpdateTracker::new(&software_version),
+            software_version,
+            tpms_tracker: TpmsTracker::from_records(&recent),
+            event_extractor: EventExtractor::default(),
+            vehicle_events: vec![],
+            conf,
+        }
+    }
+
+    /// 应用重新加载的配置, 通知渠道有变化时重建, 围栏立即重新加载
+    pub async fn set_conf(&mut self, conf: AppConfig) {
+        let notify_conf = conf.notify.clone().unwrap_or_default();
+        if conf.notify.as_ref().map(|n| &n.sinks) != self.conf.notify.as_ref().map(|n| &n.sinks)
+            || conf.api_config != self.conf.api_config
+        {
+            self.notifier =
+                Notifier::new(&notify_conf, &conf.api_config.clone().unwrap_or_default());
+        }
+        self.rules.set_rules(&notify_conf.rules);
+        self.conf = conf;
+        match PikaConnection::shared(&self.conf.pika_address).await {
+            Ok(mut pika) => self.reload_geofences(&mut pika).await,
+            Err(e) => error!("PikaConnection::shared: {e}"),
+        }
+    }
+
+    /// 保存最近的充电过程和电池健康度, 充电中每次保存都会更新, 每次充电只有一条记录
+    async fn save_charges(&mut self, pika: &mut PikaConnection) {
+        let vehicle_id = self.vehicle_id;
+        let mut charges = charging::detect_sessions(&self.charge_window);
+        if !charges.is_empty() {
+            let bills = pika
+                .load_supercharger_bills(vehicle_id)
+                .await
+                .map_err(|e| error!("pika.load_supercharger_bills: {e}"))
+                .unwrap_or_default();
+            let stored = pika
+                .load_charges(vehicle_id)
+                .await
+                .map_err(|e| error!("pika.load_charges: {e}"))
+                .unwrap_or_default();
+            let bills = supercharger::unlinked(bills, &stored, &charges);
+            charging::fill(&mut charges, &bills, &self.geofences, &self.conf.tariffs);
+        }
+        for charge in charges.iter() {
+            if let Err(e) = pika.save_charge(vehicle_id, charge).await {
+                error!("pika.save_charge: {e}");
+            }
+            if let Some(health) = battery::estimate(charge) {
+                if let Err(e) = pika.save_battery_health(vehicle_id, &health).await {
+                    error!("pika.save_battery_health: {e}");
+                }
+            }
+        }
+        charging::trim_window(&mut self.charge_window);
+    }
+
+    /// 保存已经结束的行程, 起止地址在这里解析并随行程保存
+    async fn save_trips(&mut self, pika: &mut PikaConnection) {
+        let vehicle_id = self.vehicle_id;
+        let mut trips = trip::detect_trips(&self.trip_window);
+        if !trips.is_empty() {
+            let stored = pika
+                .load_trips(vehicle_id)
+                .await
+                .map_err(|e| error!("pika.load_trips: {e}"))
+                .unwrap_or_default();
+            for t in trips.iter_mut() {
+                trip::fill(t, &stored, &self.geofences, &self.geocoder).await;
+                if let Err(e) = pika.save_trip(vehicle_id, t).await {
+                    error!("pika.save_trip: {e}");
+                }
+            }
+        }
+        trip::trim_window(&mut self.trip_window);
+    }
+
+    /// 围栏有变化时重新记录当前所在围栏, 避免把已在的围栏当作进入
+    async fn reload_geofences(&mut self, pika: &mut PikaConnection) {
+        match geofence::load_all(&self.conf, pika).await {
+            Ok(v) if v != self.geofences => {
+                self.geofences = v;
+                self.geofence_tracker = GeofenceTracker::default();
+            }
+            Ok(_) => (),
+            Err(e) => error!("geofence::load_all: {e}"),
+        }
+    }
+
+    /// now为毫秒
+    pub fn on_driving_state(&mut self, now: i64, update: DrivingState) {
+        let vehicle_id = self.vehicle_id;
+        self.geofence_events.extend(self.geofence_tracker.update(
+            &self.geofences,
+            update.timestamp,
+            update.est_lat,
+            update.est_lng,
+        ));
+        metrics().observe_driving_state(vehicle_id, &update);
+        grpc::publish_driving_state(vehicle_id, &update);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_driving_state(vehicle_id, &update);
+        }
+        for (n, sinks) in self.rules.on_driving_state(now, &update, &self.geofences) {
+            self.notifier.send(n, sinks);
+        }
+        self.pr.updates.push(update);
+    }
+
+    /// now为毫秒
+    pub fn on_vehicle_data(&mut self, now: i64, d: VehicleData) {
+        let vehicle_id = self.vehicle_id;
+        metrics().observe_vehicle_data(vehicle_id, &d);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_vehicle_data(vehicle_id, &d);
+        }
+        for (n, sinks) in self.rules.on_vehicle_data(now, &d, &self.geofences) {
+            self.notifier.send(n, sinks);
+        }
+        if let Some(vs) = &d.vehicle_state {
+            self.software_updates
+                .extend(self.software_update_tracker.update(now, vs));
+            self.vehicle_events
+                .extend(self.event_extractor.update(now, vs));
+        }
+        if let Some(sample) = tpms::sample(now, &d) {
+            self.tpms_tracker.add(tpms::local_day(now), sample);
+        }
+        self.pr.timestamp = now / 1000;
+        self.pr.snapshot = Some(d);
+    }
+
+    /// 还没有保存的区间数据
+    pub fn period_record(&self) -> &VehiclePeriodRecord {
+        &self.pr
+    }
+
+    /// 退出前保存, 还没有快照时也保存已收到的推送
+    pub async fn flush(&mut self) {
+        if self.pr.timestamp == 0 && !self.pr.updates.is_empty() {
+            self.pr.timestamp = chrono::Local::now().timestamp();
+        }
+        self.save().await;
+    }
+
+    /// 保存区间数据和期间产生的事件
+    pub async fn save(&mut self) {
+        if self.pr.timestamp == 0 {
+            return;
+        }
+        let vehicle_id = self.vehicle_id;
+        let mut pika = match PikaConnection::shared(&self.conf.pika_address).await {
+            Ok(pika) => pika,
+            Err(e) => {
+                error!("PikaConnection::shared: {e}");
+                return;
+            }
+        };
+        let timer = metrics()
+            .storage_write_seconds
+            .with_label_values(&["period_record"])
+            .start_timer();
+        match pika.save_vehicle_period_record(vehicle_id, &self.pr).await {
+            Ok(()) => (),
+            Err(e) => error!("pika.save_vehicle_period_record: {e}"),
+        }
+        timer.observe_duration();
+        info!("Save pr updates count = {}", self.pr.updates.len());
+        self.charge_window.push(charging::window_record(&self.pr));
+        self.save_charges(&mut pika).await;
+        self.trip_window.push(charging::window_record(&self.pr));
+        self.save_trips(&mut pika).await;
+        for event in self.geofence_events.drain(..) {
+            info!("geofence {} {}", event.event, event.name);
+            if let Err(e) = pika.save_geofence_event(vehicle_id, &event).await {
+                error!("pika.save_geofence_event: {e}");
+            }
+        }
+        for event in self.vehicle_events.drain(..) {
+            info!("vehicle event {} {}", event.name, event.event);
+            if let Err(e) = pika.save_vehicle_event(vehicle_id, &event).await {
+                error!("pika.save_vehicle_event: {e}");
+            }
+        }
+        for update in self.software_updates.drain(..) {
+            info!(
+                "software update {} -> {}",
+                update.from_version, update.version
+            );
+            if let Err(e) = pika.save_software_update(vehicle_id, &update).await {
+                error!("pika.save_software_update: {e}");
+            }
+        }
+        if self.software_update_tracker.version() != self.software_version {
+            let version = self.software_update_tracker.version().to_string();
+            match pika.save_software_version(vehicle_id, &version).await {
+                Ok(()) => self.software_version = version,
+                Err(e) => error!("pika.save_software_version: {e}"),
+            }
+        }
+        if let Some(daily) = self.tpms_tracker.take_daily() {
+            if let Err(e) = pika.save_tpms_daily(vehicle_id, &daily).await {
+                error!("pika.save_tpms_daily: {e}");
+            }
+            if self.tpms_tracker.needs_check() {
+                match pika.load_tpms_daily(vehicle_id).await {
+                    Ok(v) => self.rules.set_slow_leaks(tpms::slow_leaks(&v)),
+                    Err(e) => error!("pika.load_tpms_daily: {e}"),
+                }
+            }
+        }
+        self.reload_geofences(&mut pika).await;
+        self.pr.timestamp = 0;
+        self.pr.updates.clear();
+        self.pr.snapshot = None;
+    }
+}
+
+/// 已保存的当前软件版本, 没有时使用最后一条升级记录
+async fn load_software_version(pika: &mut PikaConnection, vehicle_id: i64) -> String {
+    match pika.load_software_version(vehicle_id).await {
+        Ok(Some(version)) => return version,
+        Ok(None) => (),
+        Err(e) => error!("pika.load_software_version: {e}"),
+    }
+    match pika.load_software_updates(vehicle_id).await {
+        Ok(v) => v.last().map(|u| u.version.clone()).unwrap_or_default(),
+        Err(e) => {
+            error!("pika.load_software_updates: {e}");
+            String::new()
+        }
+    }
+}
+
+/// 昨天和今天的区间数据, 启动时用于恢复还没结束的充电过程和当天的胎压
+async fn load_recent_records(
+    pika: &mut PikaConnection,
+    vehicle_id: i64,
+) -> Vec<VehiclePeriodRecord> {
+    let today = chrono::Local::now().date_naive();
+    let mut records = vec![];
+    for day in [today - chrono::Duration::days(1), today] {
+        let day = day.format("%Y%m%d").to_string().parse().unwrap_or_default();
+        match pika
+            .load_daily_vehicle_period_records(vehicle_id, day)
+            .await
+        {
+            Ok(v) => records.extend(v),
+            Err(e) => error!("pika.load_daily_vehicle_period_records: {e}"),
+        }
+    }
+    records.sort_by_key(|r| r.timestamp);
+    records
+}
+
+/// 解析stream推送, data:update返回DrivingState, 其他消息只记录日志
+pub fn handle_frame(d: &[u8]) -> Option<DrivingState> {
+    let msg = match serde_json::from_slice::<StreamMessage>(d) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("invalid stream message: {e}");
+            return None;
+        }
+    };
+    match msg.msg_type.as_str() {
+        "data:update" => {
+            let update = msg
+                .value
+                .as_deref()
+                .and_then(tesla_api::parse_driving_state);
+            if update.is_none() {
+                error!("invalid data:update {:?}", msg.value);
+            }
+            return update;
+        }
+        "data:error" => {
+            if msg.error_type.is_some() {
+                match msg.error_type.as_ref().unwrap().as_str() {
+                    "vehicle_disconnected" => {}
+                    "vehicle_error" => {
+                        if let Some(value) = &msg.value {
+                            if value.contains("Vehicle is offline") {
+                                error!("Steram vehicle is offline");
+                            }
+                        }
+                    }
+                    "client_error" => {
+                        if let Some(value) = &msg.value {
+                            if value.contains("Can't validate token.")
+                                || value.contains("unauthorized")
+                            {
+                                error!("Stream unauthorized ");
+                            }
+                        }
+                    }
+                    _ => error!("error_msg={:?}", msg),
+                }
+            }
+        }
+        "control:hello" => {}
+        _ => {
+            info!("unkown msg type, msg={:?}", msg);
+        }
+    }
+    None
+}
+
 impl VehicleMonitor {
-    pub async fn init(api: ApiClient, vehicle: Vehicle, conf: AppConfig) -> Result<Self, Error> {
+    pub async fn init(
+        api: ApiClient,
+        vehicle: Vehicle,
+        mut conf: ConfigReceiver,
+        mqtt: Option<MqttPublisher>,
+        geocoder: Arc<Geocoder>,
+    ) -> Result<Self, Error> {
         info!("monitor startup ={:?}", vehicle);
         let (exit_sender, mut exit_receiver) = tokio::sync::oneshot::channel::<String>();
-        let vm = Self { exit_sender };
-        // 60秒检查一下DrivingState
-        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(60));
+        // 默认60秒检查一下DrivingState
+        let mut period = poll_interval(&conf.borrow());
+        let mut ticker = tokio::time::interval(period);
         let vehicle_id = vehicle.vehicle_id;
 
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             use tesla_api::Error::*;
             let token = Arc::clone(&api.token);
+            let recorder = api.recorder.clone();
 
-            let mut pr = VehiclePeriodRecord::default();
+            let c = (**conf.borrow()).clone();
+            let mut state = MonitorState::new(vehicle_id, c, mqtt, geocoder).await;
+            // 发送端关闭后不再监听配置变化
+            let mut watching = true;
+            // 退出时通知stream发送close帧后结束
+            let closing = Arc::new(tokio::sync::Notify::new());
+            let stream_closing = Arc::clone(&closing);
             let s = stream! {
-            loop {
+            'outer: loop {
+                update_health(vehicle_id, |h| h.stream = "connecting".into());
                 let ws_stream = ApiClient::prepare_stream(vehicle_id, &token).await;
                 match ws_stream {
                     Ok(mut ws_stream) => {
                         info!("stream prepared.");
-                        while let Some(msg) = ws_stream.next().await {
+                        update_health(vehicle_id, |h| h.stream = "connected".into());
+                        loop {
+                            let msg = tokio::select! {
+                                _ = stream_closing.notified() => None,
+                                msg = ws_stream.next() => Some(msg),
+                            };
+                            let msg = match msg {
+                                Some(Some(msg)) => msg,
+                                Some(None) => break,
+                                None => {
+                                    if let Err(e) = ws_stream.close(None).await {
+                                        error!("close stream: {e}");
+                                    }
+                                    info!("ws stream closed by shutdown");
+                                    break 'outer;
+                                }
+                            };
                             match msg {
                                     Ok(msg) => {
                                         if msg.is_text() || msg.is_binary() {
                                             let d = msg.into_data();
-                                            let msg = serde_json::from_slice::<StreamMessage>(&d).expect("");
-                                            match msg.msg_type.as_str() {
-                                                "data:update" => {
-                                                    let arr = msg.value.as_ref().unwrap().split(",").collect_vec();
-                                                    let mut update = DrivingState::default();
-                                                    let it = arr[0].parse::<i64>().expect("");
-                                                    update.timestamp = it;
-                                                    let pf = |s: &str| {
-                                                        if s.len() == 0 {
-                                                            0.0
-                                                        } else {
-                                                            s.parse::<f64>().expect("")
-                                                        }
-                                                    };
-                                                    update.speed = if arr[1] == "" {
-                                                        0.0
-                                                    } else {
-                                                        arr[1].parse::<f64>().expect("")
-                                                    };
-                                                    update.odometer = pf(arr[2]);
-                                                    update.soc = pf(arr[3]);
-                                                    update.elevation = pf(arr[4]);
-                                                    update.est_heading = pf(arr[5]);
-                                                    update.est_lat = pf(arr[6]);
-                                                    update.est_lng = pf(arr[7]);
-                                                    update.power = pf(arr[8]);
-                                                    update.shift_state = arr[9].to_string();
-                                                    update.range = pf(arr[10]);
-                                                    update.est_range = pf(arr[11]);
-                                                    update.heading = pf(arr[12]);
-                                                    yield update;
-                                                }
-                                                "data:error" => {
-                                                    if msg.error_type.is_some() {
-                                                        match msg.error_type.as_ref().unwrap().as_str() {
-                                                            "vehicle_disconnected" => {
-                                                            }
-                                                            "vehicle_error" => {
-                                                                if let Some(value) = &msg.value {
-                                                                    if value.contains("Vehicle is offline") {
-                                                                        error!("Steram vehicle is offline");
-                                                                    }
-                                                                }
-                                                            }
-                                                            "client_error" => {
-                                                                if let Some(value) = &msg.value {
-                                                                    if value.contains("Can't validate token.")
-                                                                        || value.contains("unauthorized")
-                                                                    {
-                                                                        error!("Stream unauthorized ");
-                                                                    }
-                                                                }
-                                                            }
-                                                            _ => error!("error_msg={:?}", msg),
-                                                        }
-                                                    }
-                                                }
-                                                "control:hello" => {}
-                                                _ => {
-                                                    info!("unkown msg type, msg={:?}", msg);
-                                                }
+                                            if let Some(recorder) = &recorder {
+                                                recorder.ws(vehicle_id, &d);
+                                            }
+                                            if let Some(update) = handle_frame(&d) {
+                                                yield update;
                                             }
                                         } else {
                                             info!("ws update msg={:?}", msg.into_text());
@@ -112,11 +475,18 @@ impl VehicleMonitor {
                                 }
                         }
                         info!("ws stream closed");
+                        update_health(vehicle_id, |h| h.stream = "disconnected".into());
+                        metrics().stream_reconnect(vehicle_id);
                         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
                     }
                     Err(e)=> {
                         error!("prepare_stream: {e}");
-                        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+                        update_health(vehicle_id, |h| h.stream = "disconnected".into());
+                        metrics().stream_reconnect(vehicle_id);
+                        tokio::select! {
+                            _ = stream_closing.notified() => break 'outer,
+                            _ = tokio::time::sleep(tokio::time::Duration::from_secs(60)) => (),
+                        }
                     }
                 }
             }
@@ -126,11 +496,33 @@ impl VehicleMonitor {
                 tokio::select! {
                     _ = &mut exit_receiver => {
                         info!("Vehicle monitor exit loop");
+                        // 关闭stream, 期间收到的推送和未保存的区间数据一起写入
+                        closing.notify_one();
+                        while let Some(update) = s.next().await {
+                            state.on_driving_state(chrono::Local::now().timestamp_millis(), update);
+                        }
+                        state.flush().await;
                         break;
                     }
+                    changed = conf.changed(), if watching => {
+                        if changed.is_err() {
+                            watching = false;
+                            continue;
+                        }
+                        let c = (**conf.borrow_and_update()).clone();
+                        let interval = poll_interval(&c);
+                        if interval != period {
+                            info!("vehicle {vehicle_id} poll interval {:?}", interval);
+                            period = interval;
+                            ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                        }
+                        state.set_conf(c).await;
+                    }
                     update = s.next() => {
                         if let Some(update) = update {
-                            pr.updates.push(update);
+                            let now = chrono::Local::now().timestamp_millis();
+                            update_health(vehicle_id, |h| h.last_update = now);
+                            state.on_driving_state(now, update);
                         }
                     }
                     _instant = ticker.tick() => {
@@ -138,13 +530,15 @@ impl VehicleMonitor {
                         match vehicle_data {
                             Ok(d) => {
                                 info!("vehicle state=[{}]", d.state);
-                                cache_vehicle_data(&d)
-                                    .await
-                                    .expect("save vehicle data failed");
-                                pr.timestamp = chrono::Local::now().timestamp();
-                                pr.snapshot = Some(d);
+                                if let Err(e) = cache_vehicle_data(&d).await {
+                                    error!("cache_vehicle_data: {e}");
+                                }
+                                let now = chrono::Local::now().timestamp_millis();
+                                update_health(vehicle_id, |h| h.last_snapshot = now);
+                                state.on_vehicle_data(now, d);
                             }
                             Err(e) => {
+                                metrics().vehicle_data_error(vehicle_id, &e);
                                 match e {
                                     Unauthorized => {
                                         error!("Stream unauthorized, get new access token");
@@ -159,33 +553,202 @@ impl VehicleMonitor {
                                 error!("vehicle_data err=[{}]", e);
                             }
                         }
-                        if pr.timestamp > 0 {
-                            // 每次都重新连接以,否则会timeout error.
-                            match PikaConnection::connect(&conf.pika_address).await {
-                                Ok(mut pika) => {
-                                    match pika.save_vehicle_period_record(vehicle_id,&pr).await {
-                                        Ok(())=>(),
-                                        Err(e)=> error!("pika.save_vehicle_period_record: {e}")
-                                    }
-                                    info!("Save pr updates count = {}", pr.updates.len());
-                                    pr.timestamp = 0;
-                                    pr.updates.clear();
-                                    pr.snapshot = None;
-                                }
-                                Err(e) => error!("PikaConnection::connect: {e}")
-                            }
-                        }
+                        state.save().await;
                     }
                 }
             }
         });
 
-        Ok(vm)
+        Ok(Self {
+            exit_sender,
+            handle,
+        })
+    }
+
+    /// 等待监控任务结束, 异常退出时返回panic信息
+    pub async fn join(&mut self) -> Result<(), tokio::task::JoinError> {
+        (&mut self.handle).await
+    }
+
+    /// 通知退出并等待未保存的数据写入
+    pub async fn stop(self) {
+        let _ = self.exit_sender.send("exit".into());
+        if let Err(e) = self.handle.await {
+            error!("vehicle monitor: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+impl VehicleMonitor {
+    /// 运行指定的任务代替监控, 任务收到exit后结束
+    pub fn from_task<F, Fut>(f: F) -> Self
+    where
+        F: FnOnce(tokio::sync::oneshot::Receiver<String>) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let (exit_sender, exit_receiver) = tokio::sync::oneshot::channel::<String>();
+        Self {
+            exit_sender,
+            handle: tokio::spawn(f(exit_receiver)),
+        }
     }
 }
 
+/// 未配置时为60秒
+fn poll_interval(conf: &AppConfig) -> std::time::Duration {
+    let secs = if conf.poll_interval > 0 {
+        conf.poll_interval
+    } else {
+        60
+    };
+    std::time::Duration::from_secs(secs as u64)
+}
+
 pub async fn cache_vehicle_data(d: &VehicleData) -> Result<(), std::io::Error> {
-    let p = format!(".cache/{}/vehicle_data.json", d.vehicle_id);
+    std::fs::create_dir_all(base::data_path(&d.vehicle_id.to_string()))?;
+    let p = base::data_path(&format!("{}/vehicle_data.json", d.vehicle_id));
     std::fs::write(&p, serde_json::to_string_pretty(d).unwrap())?;
     Ok(())
 }
+
+/// 获取vehicle_data, 请求失败时返回最近缓存的快照并标记为asleep
+pub async fn vehicle_data_or_cached(
+    api: &ApiClient,
+    vehicle: &Vehicle,
+) -> Result<VehicleData, Error> {
+    match api.vehicle_data(vehicle.id).await {
+        Ok(d) => Ok(d),
+        Err(_) => {
+            let p = base::data_path(&format!("{}/vehicle_data.json", vehicle.vehicle_id));
+            let file = std::fs::File::open(p)?;
+            let mut d: VehicleData = serde_json::from_reader(std::io::BufReader::new(file))?;
+            d.state = "asleep".to_string();
+            Ok(d)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tesla_mock::{MockServer, Scenario};
+
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+    /// 启动模拟服务和监控, 等待收到场景中的全部推送
+    async fn start_monitor(scenario: Scenario, conf: AppConfig) -> (MockServer, VehicleMonitor) {
+        base::check_make_dir(&base::data_path(""));
+        let expected = scenario.updates.len();
+        let vehicle = scenario.vehicle.clone();
+        let server = MockServer::start(scenario).await;
+        let conf = AppConfig {
+            api_config: Some(server.api_config()),
+            ..conf
+        };
+        let api = server.api_client().await;
+        let mut rx = grpc::driving_states().subscribe();
+        let (_conf_tx, conf) = tokio::sync::watch::channel(Arc::new(conf));
+        let vm = VehicleMonitor::init(api, vehicle.clone(), conf, None, Default::default())
+            .await
+            .unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            let mut n = 0;
+            while n < expected {
+                let (vid, _) = rx.recv().await.unwrap();
+                if vid == vehicle.vehicle_id {
+                    n += 1;
+                }
+            }
+        })
+        .await
+        .expect("driving states");
+        (server, vm)
+    }
+
+    /// 对接模拟服务, pika不可用时监控仍然正常推送
+    #[tokio::test]
+    async fn monitor_against_mock() {
+        let conf = AppConfig {
+            pika_address: "redis://127.0.0.1:1/".into(),
+            ..Default::default()
+        };
+        let (server, vm) = start_monitor(Scenario::drive(), conf).await;
+        tokio::time::timeout(TIMEOUT, async {
+            while server.stats().vehicle_data == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("vehicle_data");
+        tokio::time::timeout(TIMEOUT, vm.stop())
+            .await
+            .expect("stop");
+    }
+
+    /// 退出时还没保存的推送也要写入区间数据
+    #[tokio::test]
+    async fn stop_saves_pending_updates() {
+        let pika = tesla_mock::FakePika::start().await;
+        let mut scenario = Scenario::drive();
+        let expected = scenario.updates.len();
+        let vehicle_id = scenario.vehicle.vehicle_id;
+        // 第一次vehicle_data失败, 之后一小时内不再轮询, 推送都留在内存中
+        scenario.failures = vec![(400, None)];
+        let conf = AppConfig {
+            pika_address: pika.address.clone(),
+            poll_interval: 3600,
+            ..Default::default()
+        };
+        let (_server, vm) = start_monitor(scenario, conf).await;
+        let prefix = format!("pr-{vehicle_id}-");
+        let days = || -> Vec<i32> {
+            pika.tables()
+                .iter()
+                .filter_map(|t| t.strip_prefix(&prefix)?.parse().ok())
+                .collect()
+        };
+        assert!(days().is_empty());
+        tokio::time::timeout(TIMEOUT, vm.stop())
+            .await
+            .expect("stop");
+        let days = days();
+        assert_eq!(days.len(), 1);
+        let saved = PikaConnection::shared(&pika.address)
+            .await
+            .unwrap()
+            .load_daily_vehicle_period_records(vehicle_id, days[0])
+            .await
+            .unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].updates.len(), expected);
+    }
+
+    /// 充电场景: 按监控的方式处理vehicle_data快照, 识别出一次完整的充电
+    #[tokio::test]
+    async fn charge_against_mock() {
+        let server = MockServer::start(Scenario::charge()).await;
+        let api = server.api_client().await;
+        let vehicle = api.vehicles().await.unwrap().remove(0);
+        let conf = AppConfig {
+            pika_address: "redis://127.0.0.1:1/".into(),
+            ..Default::default()
+        };
+        let mut state = MonitorState::new(vehicle.vehicle_id, conf, None, Default::default()).await;
+        let base = 1700000000000;
+        let mut window = vec![];
+        for i in 0..12 {
+            let d = api.vehicle_data(vehicle.id).await.unwrap();
+            state.on_vehicle_data(base + i * 60_000, d);
+            window.push(charging::window_record(&state.pr));
+        }
+        let charges = charging::detect_sessions(&window);
+        assert_eq!(charges.len(), 1);
+        let c = &charges[0];
+        assert_eq!(c.start_timestamp, base);
+        assert_eq!(c.end_timestamp, base + 9 * 60_000);
+        assert_eq!((c.start_battery_level, c.end_battery_level), (50.0, 86.0));
+        assert_eq!(c.charge_energy_added, 27.0);
+        assert_eq!(c.details.len(), 10);
+    }
+}
@@ -0,0 +1,240 @@
+//! 分层加载配置: 默认值 -> 配置文件(json/toml/yaml) -> 环境变量
+//! 环境变量名为TESLA_加大写的字段名, 嵌套字段用__连接, 如TESLA_HTTP_PORT, TESLA_API_CONFIG__API_ROOT
+use crate::pb::base::*;
+use crate::pb::tesla::ApiConfig;
+use crate::Error;
+use serde_json::{Map, Value};
+
+const ENV_PREFIX: &str = "TESLA_";
+
+fn config_err(field: &str, reason: impl std::fmt::Display) -> Error {
+    Error::ConfigErr(format!("{field}: {reason}"))
+}
+
+/// 对象按字段递归合并, 其他类型直接覆盖
+fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Object(base), Value::Object(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+fn read_file(path: &str) -> Result<Value, Error> {
+    let text = std::fs::read_to_string(path)?;
+    let ext = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    Ok(match ext {
+        "toml" => toml::from_str(&text)?,
+        "yaml" | "yml" => serde_yaml::from_str(&text)?,
+        _ => serde_json::from_str(&text)?,
+    })
+}
+
+/// 全部子配置都存在时的字段结构, 用于确定环境变量的类型
+fn schema() -> Value {
+    serde_json::to_value(AppConfig {
+        api_config: Some(Default::default()),
+        geocoder: Some(Default::default()),
+        mqtt: Some(Default::default()),
+        notify: Some(Default::default()),
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+/// 按schema中的类型解析, 数组和对象为json
+fn parse_env(name: &str, raw: String, schema: &Value) -> Result<Value, Error> {
+    match schema {
+        Value::String(_) => Ok(Value::String(raw)),
+        Value::Bool(_) => raw
+            .parse()
+            .map(Value::Bool)
+            .map_err(|_| config_err(name, format!("expected true/false, got {raw:?}"))),
+        Value::Number(_) => serde_json::from_str::<serde_json::Number>(&raw)
+            .map(Value::Number)
+            .map_err(|_| config_err(name, format!("expected number, got {raw:?}"))),
+        _ => serde_json::from_str(&raw).map_err(|e| config_err(name, e)),
+    }
+}
+
+/// 只包含已设置的环境变量, 没有设置时返回None
+fn env_overrides(
+    schema: &Value,
+    prefix: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<Option<Value>, Error> {
+    let mut m = Map::new();
+    for (key, s) in schema.as_object().into_iter().flatten() {
+        let name = format!("{prefix}{}", key.to_uppercase());
+        let v = if s.is_object() {
+            env_overrides(s, &format!("{name}__"), env)?
+        } else {
+            env(&name).map(|raw| parse_env(&name, raw, s)).transpose()?
+        };
+        if let Some(v) = v {
+            m.insert(key.clone(), v);
+        }
+    }
+    Ok((!m.is_empty()).then_some(Value::Object(m)))
+}
+
+impl AppConfig {
+    pub fn defaults() -> Self {
+        Self {
+            pika_address: "redis://127.0.0.1:9221".into(),
+            http_port: 3600,
+            api_config: Some(ApiConfig {
+                api_root: "https://owner-api.vn.cloud.tesla.cn".into(),
+                stream_path: "wss://streaming.vn.cloud.tesla.cn/streaming/".into(),
+                auth_root: "https://auth.tesla.cn".into(),
+                timeout: 30,
+                connect_timeout: 10,
+                ..Default::default()
+            }),
+            data_dir: ".cache".into(),
+            static_dir: "web/build".into(),
+            poll_interval: 60,
+            ..Default::default()
+        }
+    }
+
+    /// path为None时只使用默认值和环境变量
+    pub fn load(path: Option<&str>) -> Result<Self, Error> {
+        Self::load_with(path, |name| std::env::var(name).ok())
+    }
+
+    fn load_with(path: Option<&str>, env: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let mut v = serde_json::to_value(Self::defaults())?;
+        if let Some(path) = path {
+            merge(&mut v, read_file(path)?);
+        }
+        if let Some(over) = env_overrides(&schema(), ENV_PREFIX, &env)? {
+            merge(&mut v, over);
+        }
+        let c: Self = serde_path_to_error::deserialize(v)
+            .map_err(|e| config_err(&e.path().to_string(), e.inner()))?;
+        c.validate()?;
+        Ok(c)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.pika_address.contains("://") {
+            return Err(config_err("pika_address", "expected redis://host:port"));
+        }
+        let ports = [
+            ("http_port", self.http_port),
+            ("https_port", self.https_port),
+            ("grpc_port", self.grpc_port),
+        ];
+        for (field, port) in ports {
+            if !(0..=65535).contains(&port) {
+                return Err(config_err(field, format!("invalid port {port}")));
+            }
+        }
+        let api = self
+            .api_config
+            .as_ref()
+            .ok_or_else(|| config_err("api_config", "missing"))?;
+        for (field, url) in [
+            ("api_config.api_root", &api.api_root),
+            ("api_config.auth_root", &api.auth_root),
+        ] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(config_err(
+                    field,
+                    format!("expected http(s) url, got {url:?}"),
+                ));
+            }
+        }
+        if !api.proxy.is_empty() && !api.proxy.contains("://") {
+            return Err(config_err(
+                "api_config.proxy",
+                format!("expected scheme://host:port, got {:?}", api.proxy),
+            ));
+        }
+        for (field, secs) in [
+            ("api_config.timeout", api.timeout),
+            ("api_config.connect_timeout", api.connect_timeout),
+        ] {
+            if secs < 0 {
+                return Err(config_err(field, format!("must not be negative, got {secs}")));
+            }
+        }
+        if !api.stream_path.starts_with("ws://") && !api.stream_path.starts_with("wss://") {
+            return Err(config_err(
+                "api_config.stream_path",
+                format!("expected ws(s) url, got {:?}", api.stream_path),
+            ));
+        }
+        if self.poll_interval <= 0 {
+            return Err(config_err(
+                "poll_interval",
+                format!("must be positive, got {}", self.poll_interval),
+            ));
+        }
+        if self.data_dir.is_empty() {
+            return Err(config_err("data_dir", "must not be empty"));
+        }
+        if let Some(mqtt) = &self.mqtt {
+            if !(0..=65535).contains(&mqtt.port) {
+                return Err(config_err(
+                    "mqtt.port",
+                    format!("invalid port {}", mqtt.port),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn layered() {
+        let path = std::env::temp_dir().join(format!("tesla-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "http_port = 8080\npika_address = \"redis://pika:9221\"\n[api_config]\napi_root = \"http://127.0.0.1:1\"\n",
+        )
+        .unwrap();
+        let env: HashMap<&str, &str> = [
+            ("TESLA_HTTP_PORT", "9000"),
+            ("TESLA_API_CONFIG__AUTH_ROOT", "http://auth"),
+            ("TESLA_MQTT__HOST", "broker"),
+        ]
+        .into();
+        let path = path.to_str().unwrap();
+        let c = AppConfig::load_with(Some(path), |k| env.get(k).map(|v| v.to_string())).unwrap();
+        assert_eq!(c.http_port, 9000);
+        assert_eq!(c.pika_address, "redis://pika:9221");
+        let api = c.api_config.unwrap();
+        assert_eq!(api.api_root, "http://127.0.0.1:1");
+        assert_eq!(api.auth_root, "http://auth");
+        assert!(api.stream_path.starts_with("wss://"));
+        assert_eq!(c.mqtt.unwrap().host, "broker");
+        assert!(c.notify.is_none());
+        assert_eq!(c.data_dir, ".cache");
+
+        let env = |k: &str| (k == "TESLA_HTTP_PORT").then(|| "abc".to_string());
+        let e = AppConfig::load_with(Some(path), env).unwrap_err();
+        assert!(e.to_string().contains("TESLA_HTTP_PORT"), "{e}");
+        let env = |k: &str| (k == "TESLA_PIKA_ADDRESS").then(|| "localhost".to_string());
+        let e = AppConfig::load_with(None, env).unwrap_err();
+        assert!(e.to_string().starts_with("pika_address"), "{e}");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -4,21 +4,32 @@ pub mod pb {
     }
     pub mod base {
         tonic::include_proto!("base");
-        impl AppConfig {
-            pub fn load(path: &str) -> Result<Self, crate::Error> {
-                let file = std::fs::File::open(path)?;
-                let reader = std::io::BufReader::new(file);
-                let c = serde_json::from_reader(reader)?;
-                Ok(c)
-            }
-        }
     }
 }
+mod config;
 
 #[derive(Debug, derive_more::Display, derive_more::From)]
 pub enum Error {
     IoErr(std::io::Error),
     SerdeJsonErr(serde_json::Error),
+    TomlErr(toml::de::Error),
+    YamlErr(serde_yaml::Error),
+    /// 配置项错误, 内容以字段名开头
+    #[from(ignore)]
+    ConfigErr(String),
+}
+
+static DATA_DIR: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// 设置数据目录, 启动时调用一次, 之后的调用无效
+pub fn set_data_dir(dir: &str) {
+    let _ = DATA_DIR.set(dir.trim_end_matches('/').to_string());
+}
+
+/// 数据目录下的路径, 未设置时数据目录为.cache
+pub fn data_path(path: &str) -> String {
+    let dir = DATA_DIR.get().map(|d| d.as_str()).unwrap_or(".cache");
+    format!("{dir}/{path}")
 }
 
 // 一般性初始化日志
@@ -29,6 +40,28 @@ pub fn init_logger() {
     tracing_subscriber::fmt::init();
 }
 
+/// 在这之前保存的stream推送时间戳为秒
+const SECONDS_TIMESTAMP_BEFORE: i64 = 1675843854;
+
+/// stream推送的时间戳统一为毫秒
+pub fn timestamp_ms(timestamp: i64) -> i64 {
+    if timestamp < SECONDS_TIMESTAMP_BEFORE * 1000 {
+        timestamp * 1000
+    } else {
+        timestamp
+    }
+}
+
+/// 两个wgs84坐标间的球面距离(km)
+pub fn distance_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlng = (lng2 - lng1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
 pub fn check_make_dir(dir: &str) {
     match std::fs::create_dir_all(dir) {
         Ok(_) => (),
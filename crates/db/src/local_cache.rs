@@ -11,8 +11,8 @@ pub struct LocalStream {
 
 impl LocalStream {
     pub fn new(vehicle_id: i64) -> Self {
-        check_make_dir(&format!(".cache/{}", vehicle_id));
-        let stream_path = format!(".cache/{}/stream.dat", vehicle_id,);
+        check_make_dir(&data_path(&vehicle_id.to_string()));
+        let stream_path = data_path(&format!("{}/stream.dat", vehicle_id));
         let f = std::fs::File::options()
             .create(true)
             .append(true)
@@ -29,7 +29,7 @@ impl LocalStream {
     }
 
     pub fn load(vehicle_id: i64) -> Result<Vec<VehiclePeriodRecord>, std::io::Error> {
-        let path = format!(".cache/{}/stream.dat", vehicle_id);
+        let path = data_path(&format!("{}/stream.dat", vehicle_id));
         let mut file = File::open(&path)?;
         let mut v = vec![];
         let mut b = vec![];
@@ -1,21 +1,51 @@
 use crate::*;
-use base::pb::tesla::*;
+use base::pb::{base::*, tesla::*};
 use chrono::NaiveDateTime;
 use log::info;
 use prost::Message;
 use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
 
+/// 多路复用的连接, clone后共用同一个连接, 断开后自动重连
+#[derive(Clone)]
 pub struct PikaConnection {
-    conn: redis::aio::Connection,
+    conn: redis::aio::ConnectionManager,
+}
+
+/// 连接失败时的重试次数, 间隔为200ms起翻倍(随机抖动), 首次连接失败时尽快返回
+const CONNECT_RETRIES: usize = 2;
+
+/// 按地址缓存的共享连接
+fn shared_connections() -> &'static Mutex<HashMap<String, PikaConnection>> {
+    static CONNECTIONS: OnceLock<Mutex<HashMap<String, PikaConnection>>> = OnceLock::new();
+    CONNECTIONS.get_or_init(Default::default)
 }
 
 impl PikaConnection {
+    /// 新建连接, 一般使用shared
     pub async fn connect(address: &str) -> Result<Self, Error> {
+        let client = redis::Client::open(address)?;
         Ok(PikaConnection {
-            conn: redis::Client::open(address)?.get_async_connection().await?,
+            conn: redis::aio::ConnectionManager::new_with_backoff(client, 2, 100, CONNECT_RETRIES)
+                .await?,
         })
     }
 
+    /// 同一地址共用一个连接; 首次连接失败时返回错误, 下次调用重新连接
+    pub async fn shared(address: &str) -> Result<Self, Error> {
+        if let Some(c) = shared_connections().lock().unwrap().get(address) {
+            return Ok(c.clone());
+        }
+        let c = Self::connect(address).await?;
+        Ok(shared_connections()
+            .lock()
+            .unwrap()
+            .entry(address.to_string())
+            .or_insert(c)
+            .clone())
+    }
+
     pub async fn save_vehicle_period_record(
         &mut self,
         vid: i64,
@@ -49,4 +79,347 @@ impl PikaConnection {
         }
         Ok(v)
     }
+
+    /// 按天加载区间数据, from/to为yyyymmdd, 包含to当天, 最多一年. 每天的数据按时间排序
+    pub async fn load_vehicle_period_records_by_day(
+        &mut self,
+        vid: i64,
+        from: i32,
+        to: i32,
+    ) -> Result<Vec<(i32, Vec<VehiclePeriodRecord>)>, Error> {
+        let parse = |d: i32| chrono::NaiveDate::parse_from_str(&d.to_string(), "%Y%m%d").ok();
+        let mut days = vec![];
+        if let (Some(from), Some(to)) = (parse(from), parse(to)) {
+            for day in from.iter_days().take_while(|d| *d <= to).take(366) {
+                let day: i32 = day.format("%Y%m%d").to_string().parse().unwrap();
+                let mut v = self.load_daily_vehicle_period_records(vid, day).await?;
+                v.sort_by_key(|r| r.timestamp);
+                days.push((day, v));
+            }
+        }
+        Ok(days)
+    }
+
+    pub async fn save_trip(&mut self, vid: i64, trip: &Trip) -> Result<(), Error> {
+        let table = format!("trip-{vid}");
+        let mut b = vec![];
+        trip.encode(&mut b)?;
+        Ok(self.conn.hset(table, trip.timestamp, b).await?)
+    }
+
+    pub async fn load_trips(&mut self, vid: i64) -> Result<Vec<Trip>, Error> {
+        let table = format!("trip-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(Trip::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|t| t.timestamp);
+        Ok(v)
+    }
+
+    pub async fn save_charge(&mut self, vid: i64, charge: &HistoryCharge) -> Result<(), Error> {
+        let table = format!("charge-{vid}");
+        let mut b = vec![];
+        charge.encode(&mut b)?;
+        Ok(self.conn.hset(table, charge.start_timestamp, b).await?)
+    }
+
+    pub async fn load_charges(&mut self, vid: i64) -> Result<Vec<HistoryCharge>, Error> {
+        let table = format!("charge-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(HistoryCharge::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|c| c.start_timestamp);
+        Ok(v)
+    }
+
+    pub async fn save_geofence(&mut self, geofence: &Geofence) -> Result<(), Error> {
+        let mut b = vec![];
+        geofence.encode(&mut b)?;
+        Ok(self.conn.hset("geofences", &geofence.name, b).await?)
+    }
+
+    pub async fn delete_geofence(&mut self, name: &str) -> Result<(), Error> {
+        Ok(self.conn.hdel("geofences", name).await?)
+    }
+
+    pub async fn load_geofences(&mut self) -> Result<Vec<Geofence>, Error> {
+        let arr: Vec<Vec<u8>> = self.conn.hvals("geofences").await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(Geofence::decode(buf.as_ref())?);
+        }
+        Ok(v)
+    }
+
+    pub async fn save_geofence_event(
+        &mut self,
+        vid: i64,
+        event: &GeofenceEvent,
+    ) -> Result<(), Error> {
+        let table = format!("geofence-event-{vid}");
+        let mut b = vec![];
+        event.encode(&mut b)?;
+        let field = format!("{}-{}", event.timestamp, event.name);
+        Ok(self.conn.hset(table, field, b).await?)
+    }
+
+    pub async fn load_geofence_events(&mut self, vid: i64) -> Result<Vec<GeofenceEvent>, Error> {
+        let table = format!("geofence-event-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(GeofenceEvent::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|e| e.timestamp);
+        Ok(v)
+    }
+
+    pub async fn save_supercharger_bill(
+        &mut self,
+        vid: i64,
+        bill: &SuperchargerBill,
+    ) -> Result<(), Error> {
+        let table = format!("sc-bill-{vid}");
+        let mut b = vec![];
+        bill.encode(&mut b)?;
+        Ok(self.conn.hset(table, bill.session_id, b).await?)
+    }
+
+    pub async fn load_supercharger_bills(
+        &mut self,
+        vid: i64,
+    ) -> Result<Vec<SuperchargerBill>, Error> {
+        let table = format!("sc-bill-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(SuperchargerBill::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|b| b.start_timestamp);
+        Ok(v)
+    }
+
+    pub async fn save_battery_health(
+        &mut self,
+        vid: i64,
+        health: &BatteryHealth,
+    ) -> Result<(), Error> {
+        let table = format!("battery-{vid}");
+        let mut b = vec![];
+        health.encode(&mut b)?;
+        Ok(self.conn.hset(table, health.timestamp, b).await?)
+    }
+
+    pub async fn load_battery_health(&mut self, vid: i64) -> Result<Vec<BatteryHealth>, Error> {
+        let table = format!("battery-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(BatteryHealth::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|h| h.timestamp);
+        Ok(v)
+    }
+
+    pub async fn save_software_update(
+        &mut self,
+        vid: i64,
+        update: &SoftwareUpdateRecord,
+    ) -> Result<(), Error> {
+        let table = format!("software-update-{vid}");
+        let mut b = vec![];
+        update.encode(&mut b)?;
+        Ok(self.conn.hset(table, update.end_timestamp, b).await?)
+    }
+
+    pub async fn load_software_updates(
+        &mut self,
+        vid: i64,
+    ) -> Result<Vec<SoftwareUpdateRecord>, Error> {
+        let table = format!("software-update-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(SoftwareUpdateRecord::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|u| u.end_timestamp);
+        Ok(v)
+    }
+
+    /// 当前软件版本, 第一次看到的版本没有升级记录, 单独保存
+    pub async fn save_software_version(&mut self, vid: i64, version: &str) -> Result<(), Error> {
+        Ok(self
+            .conn
+            .set(format!("software-version-{vid}"), version)
+            .await?)
+    }
+
+    pub async fn load_software_version(&mut self, vid: i64) -> Result<Option<String>, Error> {
+        Ok(self.conn.get(format!("software-version-{vid}")).await?)
+    }
+
+    pub async fn save_tpms_daily(&mut self, vid: i64, daily: &TpmsDaily) -> Result<(), Error> {
+        let table = format!("tpms-{vid}");
+        let mut b = vec![];
+        daily.encode(&mut b)?;
+        Ok(self.conn.hset(table, daily.day, b).await?)
+    }
+
+    pub async fn load_tpms_daily(&mut self, vid: i64) -> Result<Vec<TpmsDaily>, Error> {
+        let table = format!("tpms-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.hvals(table).await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(TpmsDaily::decode(buf.as_ref())?);
+        }
+        v.sort_by_key(|d| d.day);
+        Ok(v)
+    }
+
+    /// 事件按时间保存在有序集合中, 便于分页
+    pub async fn save_vehicle_event(
+        &mut self,
+        vid: i64,
+        event: &VehicleEvent,
+    ) -> Result<(), Error> {
+        let table = format!("event-{vid}");
+        let mut b = vec![];
+        event.encode(&mut b)?;
+        Ok(self.conn.zadd(table, b, event.timestamp).await?)
+    }
+
+    /// 按时间倒序分页, page从0开始, 返回(总数, 当前页)
+    pub async fn load_vehicle_events(
+        &mut self,
+        vid: i64,
+        page: isize,
+        page_size: isize,
+    ) -> Result<(i64, Vec<VehicleEvent>), Error> {
+        let table = format!("event-{vid}");
+        let total: i64 = self.conn.zcard(&table).await?;
+        let start = page * page_size;
+        let arr: Vec<Vec<u8>> = self
+            .conn
+            .zrevrange(&table, start, start + page_size - 1)
+            .await?;
+        let mut v = vec![];
+        for buf in &arr {
+            v.push(VehicleEvent::decode(buf.as_ref())?);
+        }
+        Ok((total, v))
+    }
+
+    /// 删除hash表中field在[from, to)内的数据, field为时间戳或以"时间戳-"开头
+    async fn hdel_between(&mut self, table: &str, from: i64, to: i64) -> Result<(), Error> {
+        let fields: Vec<String> = self.conn.hkeys(table).await?;
+        let fields: Vec<String> = fields
+            .into_iter()
+            .filter(|f| {
+                f.split('-')
+                    .next()
+                    .and_then(|ts| ts.parse::<i64>().ok())
+                    .is_some_and(|ts| from <= ts && ts < to)
+            })
+            .collect();
+        if fields.is_empty() {
+            return Ok(());
+        }
+        info!("hdel_between table={table} count={}", fields.len());
+        Ok(self.conn.hdel(table, fields).await?)
+    }
+
+    /// 删除[from, to)毫秒内由区间数据生成的行程/充电/电池健康度/事件/软件升级记录
+    pub async fn delete_derived(&mut self, vid: i64, from: i64, to: i64) -> Result<(), Error> {
+        let tables = [
+            "trip",
+            "charge",
+            "battery",
+            "geofence-event",
+            "software-update",
+        ];
+        for table in tables {
+            self.hdel_between(&format!("{table}-{vid}"), from, to)
+                .await?;
+        }
+        Ok(self
+            .conn
+            .zrembyscore(format!("event-{vid}"), from, to - 1)
+            .await?)
+    }
+
+    /// 删除[from, to]日期(yyyymmdd)内的每日胎压
+    pub async fn delete_tpms_daily(&mut self, vid: i64, from: i32, to: i32) -> Result<(), Error> {
+        self.hdel_between(&format!("tpms-{vid}"), from as i64, to as i64 + 1)
+            .await
+    }
+
+    /// 解码hash表中的全部数据, 返回(表名, 条数, 解码失败条数)
+    async fn verify_table<T: Message + Default>(
+        &mut self,
+        table: String,
+    ) -> Result<(String, usize, usize), Error> {
+        let arr: Vec<Vec<u8>> = self.conn.hvals(&table).await?;
+        let bad = arr
+            .iter()
+            .filter(|buf| T::decode(buf.as_ref()).is_err())
+            .count();
+        Ok((table, arr.len(), bad))
+    }
+
+    /// 检查车辆的全部数据能否解码, 返回每张表的(表名, 条数, 解码失败条数)
+    pub async fn verify(&mut self, vid: i64) -> Result<Vec<(String, usize, usize)>, Error> {
+        let mut v = vec![];
+        let mut tables: Vec<String> = self.conn.keys(format!("pr-{vid}-*")).await?;
+        tables.sort();
+        for table in tables {
+            v.push(self.verify_table::<VehiclePeriodRecord>(table).await?);
+        }
+        v.push(self.verify_table::<Trip>(format!("trip-{vid}")).await?);
+        v.push(
+            self.verify_table::<HistoryCharge>(format!("charge-{vid}"))
+                .await?,
+        );
+        v.push(
+            self.verify_table::<BatteryHealth>(format!("battery-{vid}"))
+                .await?,
+        );
+        v.push(
+            self.verify_table::<GeofenceEvent>(format!("geofence-event-{vid}"))
+                .await?,
+        );
+        v.push(
+            self.verify_table::<SuperchargerBill>(format!("sc-bill-{vid}"))
+                .await?,
+        );
+        v.push(
+            self.verify_table::<SoftwareUpdateRecord>(format!("software-update-{vid}"))
+                .await?,
+        );
+        v.push(
+            self.verify_table::<TpmsDaily>(format!("tpms-{vid}"))
+                .await?,
+        );
+        v.push(self.verify_table::<Geofence>("geofences".into()).await?);
+        let table = format!("event-{vid}");
+        let arr: Vec<Vec<u8>> = self.conn.zrange(&table, 0, -1).await?;
+        let bad = arr
+            .iter()
+            .filter(|buf| VehicleEvent::decode(buf.as_ref()).is_err())
+            .count();
+        v.push((table, arr.len(), bad));
+        Ok(v)
+    }
+
+    /// pika的compact命令, 手动触发rocksdb压缩
+    pub async fn compact(&mut self) -> Result<(), Error> {
+        Ok(redis::cmd("COMPACT").query_async(&mut self.conn).await?)
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        Ok(redis::cmd("PING").query_async(&mut self.conn).await?)
+    }
 }
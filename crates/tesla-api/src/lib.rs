@@ -1,7 +1,10 @@
+pub mod record;
+
 use base::pb::tesla::*;
 use futures_util::{SinkExt, StreamExt};
 use itertools::Itertools;
-use log::{error, info};
+use log::{error, info, warn};
+use record::Recorder;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 use std::sync::Arc;
@@ -10,6 +13,8 @@ use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::protocol::Me
 
 #[derive(Debug, derive_more::Display, derive_more::From)]
 pub enum Error {
+    /// 超时按Timeout返回, 见From<reqwest::Error>
+    #[from(ignore)]
     ReqwestError(reqwest::Error),
     EmptyTeslaAuthSid,
     Unauthorized,
@@ -23,6 +28,114 @@ pub enum Error {
     WsErr(tokio_tungstenite::tungstenite::Error),
     AccessTokenExpired,
     SerdeJsonErr(serde_json::Error),
+    IoErr(std::io::Error),
+    #[from(ignore)]
+    ChargingHistoryErr(String),
+    /// 请求超时
+    Timeout,
+    NotFound,
+    /// 429, Retry-After秒数, 没有时为0
+    #[from(ignore)]
+    RateLimited(u64),
+    /// 5xx
+    #[from(ignore)]
+    ServerError(u16),
+    /// 其他非2xx状态码
+    #[from(ignore)]
+    HttpStatus(u16),
+}
+
+impl Error {
+    /// 错误类型名称, 用于统计
+    pub fn kind(&self) -> &'static str {
+        use Error::*;
+        match self {
+            ReqwestError(_) => "ReqwestError",
+            EmptyTeslaAuthSid => "EmptyTeslaAuthSid",
+            Unauthorized => "Unauthorized",
+            VehicleUnavailable => "VehicleUnavailable",
+            VehicleOffline => "VehicleOffline",
+            StreamWebSocketClosed => "StreamWebSocketClosed",
+            InvalidEmail => "InvalidEmail",
+            InvalidPassword => "InvalidPassword",
+            LocalChannelClosed => "LocalChannelClosed",
+            AuthFailed(_) => "AuthFailed",
+            WsErr(_) => "WsErr",
+            AccessTokenExpired => "AccessTokenExpired",
+            SerdeJsonErr(_) => "SerdeJsonErr",
+            IoErr(_) => "IoErr",
+            ChargingHistoryErr(_) => "ChargingHistoryErr",
+            Timeout => "Timeout",
+            NotFound => "NotFound",
+            RateLimited(_) => "RateLimited",
+            ServerError(_) => "ServerError",
+            HttpStatus(_) => "HttpStatus",
+        }
+    }
+
+    /// 重试前等待的时间, 不应重试时返回None; attempt从0开始
+    fn retry_delay(&self, attempt: u32) -> Option<std::time::Duration> {
+        let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+        match self {
+            Error::RateLimited(0) => Some(backoff),
+            Error::RateLimited(secs) if *secs <= MAX_RETRY_AFTER => {
+                Some(std::time::Duration::from_secs(*secs))
+            }
+            Error::ServerError(_) | Error::Timeout => Some(backoff),
+            Error::ReqwestError(e) if e.is_connect() => Some(backoff),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Error::Timeout
+        } else {
+            Error::ReqwestError(e)
+        }
+    }
+}
+
+/// 第一次重试前的等待时间, 之后每次翻倍
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+/// Retry-After超过该秒数时不在请求内等待, 直接返回RateLimited
+const MAX_RETRY_AFTER: u64 = 30;
+
+/// 按状态码区分错误, 2xx返回Ok
+fn check_status(status: reqwest::StatusCode, retry_after: Option<u64>) -> Result<(), Error> {
+    match status.as_u16() {
+        200..=299 => Ok(()),
+        401 => Err(Error::Unauthorized),
+        404 => Err(Error::NotFound),
+        // 车辆休眠或离线
+        408 => Err(Error::VehicleUnavailable),
+        429 => Err(Error::RateLimited(retry_after.unwrap_or_default())),
+        s @ 500..=599 => Err(Error::ServerError(s)),
+        s => Err(Error::HttpStatus(s)),
+    }
+}
+
+/// Retry-After为秒数或HTTP日期
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    let v = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = v.parse() {
+        return Some(secs);
+    }
+    let t = chrono::DateTime::parse_from_rfc2822(v).ok()?;
+    Some((t.timestamp() - chrono::Utc::now().timestamp()).max(0) as u64)
+}
+
+/// 单次调用的超时和重试次数, 只重试幂等的请求
+#[derive(Debug, Clone, Copy)]
+struct CallPolicy {
+    timeout: std::time::Duration,
+    retries: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -46,11 +159,30 @@ pub struct TokenState {
     pub cookie: String,
     token: AccessTokenResponse,
     conf: ApiConfig,
+    client: reqwest::Client,
+}
+
+/// 按ApiConfig创建HTTP客户端, 超时为0时使用默认值; 同一个TokenState下的请求共用以复用连接
+pub fn http_client(conf: &ApiConfig) -> Result<reqwest::Client, Error> {
+    let secs = |v: i32, default: u64| {
+        std::time::Duration::from_secs(if v > 0 { v as u64 } else { default })
+    };
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent("tesla-api")
+        .timeout(secs(conf.timeout, 30))
+        .connect_timeout(secs(conf.connect_timeout, 10))
+        .pool_idle_timeout(std::time::Duration::from_secs(90))
+        .tcp_keepalive(std::time::Duration::from_secs(60));
+    if !conf.proxy.is_empty() {
+        builder = builder.proxy(reqwest::Proxy::all(&conf.proxy)?);
+    }
+    Ok(builder.build()?)
 }
 
 impl TokenState {
     pub async fn new(conf: &ApiConfig, cookie: String) -> Result<Self, Error> {
-        let file = std::fs::File::open(".cache/token.json");
+        let file = std::fs::File::open(base::data_path("token.json"));
         let token = match file {
             Ok(file) => {
                 let reader = std::io::BufReader::new(file);
@@ -63,12 +195,32 @@ impl TokenState {
             token,
             conf: conf.clone(),
             cookie,
+            client: http_client(conf)?,
         })
     }
 
+    /// 使用指定的token, 不读取缓存文件
+    pub fn from_token(
+        conf: &ApiConfig,
+        token: AccessTokenResponse,
+        cookie: String,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            token,
+            conf: conf.clone(),
+            cookie,
+            client: http_client(conf)?,
+        })
+    }
+
+    /// 共用的HTTP客户端
+    pub fn client(&self) -> reqwest::Client {
+        self.client.clone()
+    }
+
     fn cache_token(token: &AccessTokenResponse) -> std::io::Result<()> {
         std::fs::write(
-            ".cache/token.json",
+            base::data_path("token.json"),
             serde_json::to_string_pretty(&token).unwrap(),
         )
     }
@@ -92,12 +244,8 @@ impl TokenState {
             refresh_token: self.token.refresh_token.clone(),
             scope: scope.to_string(),
         };
-        let client = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .user_agent("tesla-api")
-            .build()
-            .unwrap();
-        let mut resp = client
+        let mut resp = self
+            .client
             .post(url)
             .json(&req)
             .send()
@@ -122,6 +270,12 @@ impl TokenState {
         TokenState::cache_token(&self.token)
     }
 
+    /// access token剩余有效期(秒)
+    pub fn expires_in(&self) -> Option<i64> {
+        let ct = self.token.create_timestamp?;
+        Some(ct + self.token.expires_in - chrono::Local::now().timestamp())
+    }
+
     pub fn is_valid(&self) -> bool {
         self.token.access_token.len() > 0 && self.token.refresh_token.len() > 0
     }
@@ -145,53 +299,177 @@ impl TokenState {
 pub struct ApiClient {
     pub conf: ApiConfig,
     pub token: std::sync::Arc<tokio::sync::Mutex<TokenState>>,
+    /// 开启录制时保存原始响应和stream推送
+    pub recorder: Option<Arc<Recorder>>,
+    client: reqwest::Client,
 }
 impl ApiClient {
     pub async fn init(
         conf: &ApiConfig,
         token: std::sync::Arc<tokio::sync::Mutex<TokenState>>,
     ) -> Self {
+        let client = token.lock().await.client();
         ApiClient {
             conf: conf.clone(),
             token,
+            recorder: None,
+            client,
+        }
+    }
+
+    pub fn with_recorder(mut self, recorder: Option<Arc<Recorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// 配置的请求超时, 默认30秒
+    fn call_timeout(&self) -> std::time::Duration {
+        let secs = if self.conf.timeout > 0 {
+            self.conf.timeout as u64
+        } else {
+            30
+        };
+        std::time::Duration::from_secs(secs)
+    }
+
+    /// 查询类GET请求
+    fn read_policy(&self) -> CallPolicy {
+        CallPolicy {
+            timeout: self.call_timeout(),
+            retries: 3,
+        }
+    }
+
+    /// 发送请求并读取响应内容, 开启录制时写入录制文件(包括失败的响应)
+    /// 超时/429/5xx/连接失败按policy重试, 429时按Retry-After等待
+    async fn send(
+        &self,
+        path: &str,
+        rb: reqwest::RequestBuilder,
+        policy: CallPolicy,
+    ) -> Result<String, Error> {
+        let rb = rb.timeout(policy.timeout);
+        let mut attempt = 0;
+        loop {
+            let result = match rb.try_clone() {
+                Some(rb) => self.send_once(path, rb).await,
+                None => return self.send_once(path, rb).await,
+            };
+            let err = match &result {
+                Err(e) if attempt < policy.retries => e,
+                _ => return result,
+            };
+            let wait = match err.retry_delay(attempt) {
+                Some(wait) => wait,
+                None => return result,
+            };
+            warn!("{path}: {err}, retry in {wait:?}");
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(&self, path: &str, rb: reqwest::RequestBuilder) -> Result<String, Error> {
+        let resp = rb.send().await?;
+        let status = resp.status();
+        let retry_after = parse_retry_after(resp.headers());
+        let text = resp.text().await?;
+        if let Some(recorder) = &self.recorder {
+            recorder.http(path, status.as_u16(), &text);
+        }
+        check_status(status, retry_after)?;
+        Ok(text)
+    }
+
+    fn record_ws(&self, vehicle_id: i64, frame: &[u8]) {
+        if let Some(recorder) = &self.recorder {
+            recorder.ws(vehicle_id, frame);
         }
     }
 
     async fn make_api_request_builder(&self, path: &str) -> reqwest::RequestBuilder {
         let url = format!("{}{}", self.conf.api_root.to_string(), path);
+        self.make_request_builder(reqwest::Method::GET, &url).await
+    }
+
+    async fn make_request_builder(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+    ) -> reqwest::RequestBuilder {
         let access_token = { self.token.lock().await.token.access_token.clone() };
-        reqwest::Client::new()
-            .get(url)
+        self.client
+            .request(method, url)
             .header("Authorization", format!("Bearer {}", access_token))
     }
 
-    pub async fn charge_state(&self) -> Result<ChargeResponse, Error> {
-        // let url = "https://www.tesla.cn/teslaaccount/charging/api/history";
-        let url = "https://www.tesla.cn/teslaaccount/charging/api/history?startTime=2022-01-21T03%3A29%3A45.613Z&endTime=2023-01-21T03%3A29%3A45.613Z";
-
-        let client = reqwest::Client::new();
-        let resp = client.get(url).send().await?;
-        if resp.status() == 401 {
-            return Err(Error::Unauthorized);
+    fn charging_history_root(&self) -> String {
+        if !self.conf.charging_history_root.is_empty() {
+            return self.conf.charging_history_root.clone();
         }
-        let resp_data = resp.json::<ChargeResponse>().await?;
+        self.conf.auth_root.replace("//auth.", "//www.")
+    }
+
+    /// 超充账单, 分页查询, page从1开始
+    pub async fn charging_history(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        page: i32,
+        page_size: i32,
+    ) -> Result<ChargeResponse, Error> {
+        let fmt = |t: chrono::DateTime<chrono::Utc>| {
+            t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        };
+        let url = format!(
+            "{}/teslaaccount/charging/api/history",
+            self.charging_history_root()
+        );
+        let rb = self
+            .make_request_builder(reqwest::Method::GET, &url)
+            .await
+            .query(&[
+                ("startTime", fmt(start)),
+                ("endTime", fmt(end)),
+                ("pageNumber", page.to_string()),
+                ("pageSize", page_size.to_string()),
+            ]);
+        let text = self.send(&url, rb, self.read_policy()).await?;
+        let resp_data = serde_json::from_str::<ChargeResponse>(&text)?;
         Ok(resp_data)
     }
 
+    /// 时间段内全部超充账单
+    pub async fn charging_history_all(
+        &self,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<ChargeRecord>, Error> {
+        const PAGE_SIZE: i32 = 50;
+        let mut v = vec![];
+        for page in 1.. {
+            let resp = self.charging_history(start, end, page, PAGE_SIZE).await?;
+            if !resp.success {
+                return Err(Error::ChargingHistoryErr(resp.message));
+            }
+            let n = resp.data.len();
+            v.extend(resp.data);
+            if n < PAGE_SIZE as usize {
+                break;
+            }
+        }
+        Ok(v)
+    }
+
     pub async fn users_me(&self) -> Result<UsersMeResponse, Error> {
         #[derive(Debug, Deserialize)]
         struct XResponse {
             response: UsersMeResponse,
         }
-        let resp = self
-            .make_api_request_builder("/api/1/users/me")
-            .await
-            .send()
-            .await?;
-        if resp.status() == 401 {
-            return Err(Error::Unauthorized);
-        }
-        let resp = resp.json::<XResponse>().await?;
+        let path = "/api/1/users/me";
+        let rb = self.make_api_request_builder(path).await;
+        let text = self.send(path, rb, self.read_policy()).await?;
+        let resp = serde_json::from_str::<XResponse>(&text)?;
         Ok(resp.response)
     }
 
@@ -201,20 +479,16 @@ impl ApiClient {
             response: Vec<Vehicle>,
             count: i32,
         }
-        let resp = self
-            .make_api_request_builder("/api/1/vehicles")
-            .await
-            .send()
-            .await?;
-        if resp.status() == 401 {
-            return Err(Error::Unauthorized);
-        }
-        // let text = resp.text().await?;
-        // let j: serde_json::Value = serde_json::from_str(&text).unwrap();
-        // panic!("{}", serde_json::to_string_pretty(&j).unwrap());
-        let resp = resp.json::<XResponse>().await?;
+        let path = "/api/1/vehicles";
+        let rb = self.make_api_request_builder(path).await;
+        let text = self.send(path, rb, self.read_policy()).await?;
+        let resp = serde_json::from_str::<XResponse>(&text)?;
         if resp.response.len() as i32 != resp.count {
-            panic!("resp={:?}", resp);
+            warn!(
+                "vehicles count={} but {} returned",
+                resp.count,
+                resp.response.len()
+            );
         }
         Ok(resp.response)
     }
@@ -226,17 +500,14 @@ impl ApiClient {
             //            error: Option<String>,
             //           error_description: Option<String>,
         }
-        let resp = self
-            .make_api_request_builder(&format!("/api/1/vehicles/{id}/vehicle_data"))
-            .await
-            .send()
-            .await?;
-        if resp.status() == 401 {
-            return Err(Error::Unauthorized);
-        }
-        let text = resp.text().await?;
-        // let j: serde_json::Value = serde_json::from_str(&text).unwrap();
-        // panic!("{}", serde_json::to_string_pretty(&j).unwrap());
+        let path = format!("/api/1/vehicles/{id}/vehicle_data");
+        let rb = self.make_api_request_builder(&path).await;
+        // 每个轮询周期调用一次, 只重试一次
+        let policy = CallPolicy {
+            retries: 1,
+            ..self.read_policy()
+        };
+        let text = self.send(&path, rb, policy).await?;
         let resp = serde_json::from_str::<XResponse>(&text);
         if let Err(e) = &resp {
             error!("{text}");
@@ -255,13 +526,16 @@ impl ApiClient {
         struct XResponse {
             response: VehicleData,
         }
-        let resp = self
-            .make_api_request_builder(&format!("/api/1/vehicles/{id}/wake_up"))
-            .await
-            .send()
-            .await?
-            .json::<XResponse>()
-            .await?;
+        let path = format!("/api/1/vehicles/{id}/wake_up");
+        let url = format!("{}{}", self.conf.api_root, path);
+        let rb = self.make_request_builder(reqwest::Method::POST, &url).await;
+        // 不是幂等请求, 不重试
+        let policy = CallPolicy {
+            retries: 0,
+            ..self.read_policy()
+        };
+        let text = self.send(&path, rb, policy).await?;
+        let resp = serde_json::from_str::<XResponse>(&text)?;
         Ok(resp.response)
     }
 
@@ -311,54 +585,41 @@ impl ApiClient {
         output: &tokio::sync::mpsc::Sender<DrivingState>,
     ) -> Result<(), Error> {
         let mut ws_stream = ApiClient::prepare_stream(vehicle_id, &self.token).await?;
-        let log_path = format!(
-            ".cache/{}/logs/{}.log",
+        let log_path = base::data_path(&format!(
+            "{}/logs/{}.log",
             vehicle_id,
             chrono::Local::now().format("%Y_%m_%d"),
-        );
+        ));
         let mut f = std::fs::File::options()
             .create(true)
             .append(true)
-            .open(&log_path)
-            .expect("open append.log failed.");
+            .open(&log_path)?;
         while let Some(msg) = ws_stream.next().await {
             match msg {
                 Ok(msg) => {
                     if msg.is_text() || msg.is_binary() {
                         let d = msg.into_data();
-                        let msg = serde_json::from_slice::<StreamMessage>(&d).expect("");
+                        self.record_ws(vehicle_id, &d);
+                        let msg = match serde_json::from_slice::<StreamMessage>(&d) {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                error!("invalid stream message: {e}");
+                                continue;
+                            }
+                        };
                         match msg.msg_type.as_str() {
                             "data:update" => {
-                                let arr = msg.value.as_ref().unwrap().split(",").collect_vec();
-                                let mut update = DrivingState::default();
-                                let it = arr[0].parse::<i64>().expect("");
-                                update.timestamp = it;
-                                let pf = |s: &str| {
-                                    if s.len() == 0 {
-                                        0.0
-                                    } else {
-                                        s.parse::<f64>().expect("")
-                                    }
-                                };
-                                update.speed = if arr[1] == "" {
-                                    0.0
-                                } else {
-                                    arr[1].parse::<f64>().expect("")
-                                };
-                                update.odometer = pf(arr[2]);
-                                update.soc = pf(arr[3]);
-                                update.elevation = pf(arr[4]);
-                                update.est_heading = pf(arr[5]);
-                                update.est_lat = pf(arr[6]);
-                                update.est_lng = pf(arr[7]);
-                                update.power = pf(arr[8]);
-                                update.shift_state = arr[9].to_string();
-                                update.range = pf(arr[10]);
-                                update.est_range = pf(arr[11]);
-                                update.heading = pf(arr[12]);
-                                let json = serde_json::to_string(&update).unwrap();
-                                f.write(json.as_bytes()).unwrap();
-                                f.write(b"\r\n").unwrap();
+                                let update =
+                                    match msg.value.as_deref().and_then(parse_driving_state) {
+                                        Some(update) => update,
+                                        None => {
+                                            error!("invalid data:update {:?}", msg.value);
+                                            continue;
+                                        }
+                                    };
+                                let json = serde_json::to_string(&update)?;
+                                f.write_all(json.as_bytes())?;
+                                f.write_all(b"\r\n")?;
                                 if let Err(_e) = output.send(update).await {
                                     return Err(Error::LocalChannelClosed);
                                 }
@@ -394,9 +655,9 @@ impl ApiClient {
                                         }
                                         _ => error!("error_msg={:?}", msg),
                                     }
-                                    let json = serde_json::to_string(&msg).unwrap();
-                                    f.write(json.as_bytes()).unwrap();
-                                    f.write(b"\r\n").unwrap();
+                                    let json = serde_json::to_string(&msg)?;
+                                    f.write_all(json.as_bytes())?;
+                                    f.write_all(b"\r\n")?;
                                 }
                             }
                             "control:hello" => {}
@@ -418,6 +679,37 @@ impl ApiClient {
     }
 }
 
+/// 解析data:update推送的value, 字段顺序同make_ws_connect_message; 字段不全或无法解析时返回None
+pub fn parse_driving_state(value: &str) -> Option<DrivingState> {
+    let arr = value.split(",").collect_vec();
+    if arr.len() < 13 {
+        return None;
+    }
+    let pf = |s: &str| {
+        if s.is_empty() {
+            Some(0.0)
+        } else {
+            s.parse::<f64>().ok()
+        }
+    };
+    Some(DrivingState {
+        timestamp: arr[0].parse::<i64>().ok()?,
+        speed: pf(arr[1])?,
+        odometer: pf(arr[2])?,
+        soc: pf(arr[3])?,
+        elevation: pf(arr[4])?,
+        est_heading: pf(arr[5])?,
+        est_lat: pf(arr[6])?,
+        est_lng: pf(arr[7])?,
+        power: pf(arr[8])?,
+        shift_state: arr[9].to_string(),
+        range: pf(arr[10])?,
+        est_range: pf(arr[11])?,
+        heading: pf(arr[12])?,
+        ..Default::default()
+    })
+}
+
 #[derive(Debug, Serialize)]
 pub struct ConnectMessage {
     pub msg_type: String,
@@ -486,6 +778,37 @@ pub struct ChargeRecord {
     pub vin: String,
 }
 
+fn parse_bill_time(s: &str) -> i64 {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_default()
+}
+
+impl ChargeRecord {
+    pub fn to_bill(&self) -> SuperchargerBill {
+        SuperchargerBill {
+            session_id: self.session_id,
+            vin: self.vin.clone(),
+            site_location_name: self.site_location_name.clone(),
+            start_timestamp: parse_bill_time(&self.charge_start_date_time),
+            end_timestamp: parse_bill_time(&self.charge_stop_date_time),
+            currency: self
+                .fees
+                .first()
+                .map(|f| f.currency_code.clone())
+                .unwrap_or_default(),
+            total_due: self.fees.iter().map(|f| f.total_due).sum(),
+            energy: self
+                .fees
+                .iter()
+                .filter(|f| f.uom.eq_ignore_ascii_case("kwh"))
+                .map(|f| f.usage_base + f.usage_tier1)
+                .sum(),
+            is_paid: self.fees.iter().all(|f| f.is_paid),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChargeResponse {
@@ -502,6 +825,7 @@ mod tests {
     use super::*;
 
     #[tokio::test]
+    #[ignore = "需要访问外网"]
     async fn it_works() {
         init_logger();
         let url = format!("https://owner-api.vn.cloud.tesla.cn/api/1/vehicles");
@@ -515,4 +839,22 @@ mod tests {
         let text = resp.text().await.unwrap();
         info!("{text} status={status_code}");
     }
+
+    #[test]
+    fn parse_invalid_driving_state() {
+        let ds = parse_driving_state("1700000000000,36,1000.5,80,10,90,31.2,121.4,15,D,240,220,90")
+            .unwrap();
+        assert_eq!(ds.timestamp, 1700000000000);
+        assert_eq!(ds.shift_state, "D");
+        assert_eq!(ds.heading, 90.0);
+        // 空字段为0
+        let ds = parse_driving_state("1700000000000,,1000.5,80,10,90,31.2,121.4,,,240,220,90");
+        assert_eq!(ds.unwrap().speed, 0.0);
+        assert!(parse_driving_state("").is_none());
+        assert!(parse_driving_state("1700000000000,36,1000.5").is_none());
+        assert!(parse_driving_state("x,36,1000.5,80,10,90,31.2,121.4,15,D,240,220,90").is_none());
+        assert!(
+            parse_driving_state("1700000000000,36,?,80,10,90,31.2,121.4,15,D,240,220,90").is_none()
+        );
+    }
 }
@@ -0,0 +1,81 @@
+//! 录制API响应和stream推送, 用于离线回放
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// 录制文件每行一条
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordEntry {
+    /// 毫秒
+    pub timestamp: i64,
+    /// http/ws
+    pub kind: String,
+    /// http为请求路径, ws为订阅的vehicle_id
+    pub path: String,
+    #[serde(default)]
+    pub status: u16,
+    pub body: String,
+}
+
+pub struct Recorder {
+    file: std::sync::Mutex<std::fs::File>,
+}
+
+impl Recorder {
+    /// 追加写入path
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::File::options()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: std::sync::Mutex::new(file),
+        })
+    }
+
+    fn write(&self, entry: RecordEntry) {
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("record: {e}");
+        }
+    }
+
+    pub fn http(&self, path: &str, status: u16, body: &str) {
+        self.write(RecordEntry {
+            timestamp: chrono::Local::now().timestamp_millis(),
+            kind: "http".into(),
+            path: path.into(),
+            status,
+            body: body.into(),
+        });
+    }
+
+    pub fn ws(&self, vehicle_id: i64, frame: &[u8]) {
+        self.write(RecordEntry {
+            timestamp: chrono::Local::now().timestamp_millis(),
+            kind: "ws".into(),
+            path: vehicle_id.to_string(),
+            status: 0,
+            body: String::from_utf8_lossy(frame).into(),
+        });
+    }
+}
+
+/// 读取录制文件, 按时间排序
+pub fn load(path: &str) -> std::io::Result<Vec<RecordEntry>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = vec![];
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<RecordEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => error!("record line: {e}"),
+        }
+    }
+    entries.sort_by_key(|e| e.timestamp);
+    Ok(entries)
+}
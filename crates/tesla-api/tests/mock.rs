@@ -0,0 +1,98 @@
+//! 对接tesla-mock的模拟服务
+use tesla_api::{ApiClient, Error};
+
+async fn client(scenario: tesla_mock::Scenario) -> (tesla_mock::MockServer, ApiClient) {
+    base::check_make_dir(&base::data_path(""));
+    let server = tesla_mock::MockServer::start(scenario).await;
+    let api = server.api_client().await;
+    (server, api)
+}
+
+#[tokio::test]
+async fn mock_vehicle_data() {
+    let (_server, api) = client(tesla_mock::Scenario::drive()).await;
+    let vehicles = api.vehicles().await.unwrap();
+    assert_eq!(vehicles.len(), 1);
+    let d = api.vehicle_data(vehicles[0].id).await.unwrap();
+    assert_eq!(d.vehicle_id, vehicles[0].vehicle_id);
+    assert_eq!(d.vehicle_state.unwrap().car_version, "2023.44.30.8");
+    assert_eq!(api.users_me().await.unwrap().email, "mock@example.com");
+}
+
+#[tokio::test]
+async fn mock_token_expiry() {
+    let (server, api) = client(tesla_mock::Scenario::token_expiry()).await;
+    assert!(matches!(api.vehicles().await, Err(Error::Unauthorized)));
+    api.token.lock().await.refresh_token().await.unwrap();
+    assert_eq!(api.vehicles().await.unwrap().len(), 1);
+    assert_eq!(server.stats().token_refreshes, 1);
+}
+
+#[tokio::test]
+async fn mock_sleep_and_wake_up() {
+    let (_server, api) = client(tesla_mock::Scenario::sleep()).await;
+    let v = &api.vehicles().await.unwrap()[0];
+    assert_eq!(v.state, "asleep");
+    assert!(matches!(
+        api.vehicle_data(v.id).await,
+        Err(Error::VehicleUnavailable)
+    ));
+    let (tx, _rx) = tokio::sync::mpsc::channel(16);
+    base::check_make_dir(&base::data_path(&format!("{}/logs", v.vehicle_id)));
+    assert!(matches!(
+        api.stream(v.vehicle_id, &tx).await,
+        Err(Error::VehicleOffline)
+    ));
+    assert_eq!(api.wake_up(v.id).await.unwrap().state, "online");
+    assert!(api.vehicle_data(v.id).await.is_ok());
+}
+
+#[tokio::test]
+async fn mock_retries() {
+    let (server, api) = client(tesla_mock::Scenario::default()).await;
+    server.update(|s| s.failures = vec![(503, None), (429, Some(1))]);
+    assert_eq!(api.vehicles().await.unwrap().len(), 1);
+    assert_eq!(server.stats().failures, 2);
+
+    assert!(matches!(api.vehicle_data(1).await, Err(Error::NotFound)));
+    // vehicle_data只重试一次
+    server.update(|s| s.failures = vec![(502, None), (500, None), (500, None)]);
+    let id = api.vehicles().await.unwrap()[0].id;
+    assert!(api.vehicle_data(id).await.is_ok());
+    server.update(|s| s.failures = vec![(500, None), (500, None)]);
+    assert!(matches!(
+        api.vehicle_data(id).await,
+        Err(Error::ServerError(500))
+    ));
+    // 不可重试的状态码
+    server.update(|s| s.failures = vec![(400, None)]);
+    assert!(matches!(api.users_me().await, Err(Error::HttpStatus(400))));
+    assert_eq!(server.stats().failures, 8);
+}
+
+#[tokio::test]
+async fn mock_stream() {
+    let scenario = tesla_mock::Scenario::disconnects();
+    let expected = scenario.updates.clone();
+    let (server, api) = client(scenario).await;
+    let vehicle_id = api.vehicles().await.unwrap()[0].vehicle_id;
+    base::check_make_dir(&base::data_path(&format!("{vehicle_id}/logs")));
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    // 每3条断开一次, 重连后继续推送
+    let mut closed = 0;
+    loop {
+        match api.stream(vehicle_id, &tx).await {
+            Err(Error::StreamWebSocketClosed) => closed += 1,
+            Ok(()) => break,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    drop(tx);
+    let mut received = vec![];
+    while let Some(ds) = rx.recv().await {
+        received.push(ds);
+    }
+    assert_eq!(received, expected);
+    assert_eq!(closed, 3);
+    assert_eq!(server.stats().stream_connections, 4);
+}
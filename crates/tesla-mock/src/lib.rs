@@ -0,0 +1,395 @@
+//! 本地模拟的Tesla API和stream服务, 按场景返回数据, 用于集成测试
+mod pika;
+mod scenario;
+pub use pika::FakePika;
+pub use scenario::*;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base::pb::tesla::*;
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+
+/// 请求计数, 测试中用于断言
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    pub token_refreshes: usize,
+    pub unauthorized: usize,
+    pub vehicle_data: usize,
+    pub wake_ups: usize,
+    pub stream_connections: usize,
+    pub failures: usize,
+}
+
+struct MockState {
+    scenario: Scenario,
+    access_token: String,
+    token_seq: usize,
+    snapshot_index: usize,
+    update_index: usize,
+    stats: Stats,
+}
+
+type SharedState = Arc<Mutex<MockState>>;
+
+pub struct MockServer {
+    /// http://127.0.0.1:port
+    pub api_root: String,
+    /// ws://127.0.0.1:port/streaming/
+    pub stream_path: String,
+    state: SharedState,
+}
+
+impl MockServer {
+    pub async fn start(scenario: Scenario) -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            scenario,
+            access_token: ACCESS_TOKEN.to_string(),
+            token_seq: 0,
+            snapshot_index: 0,
+            update_index: 0,
+            stats: Stats::default(),
+        }));
+        let app = Router::new()
+            .route("/oauth2/v3/token", post(token))
+            .route("/api/1/users/me", get(users_me))
+            .route("/api/1/vehicles", get(vehicles))
+            .route("/api/1/vehicles/:id/vehicle_data", get(vehicle_data))
+            .route("/api/1/vehicles/:id/wake_up", get(wake_up).post(wake_up))
+            .with_state(Arc::clone(&state));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_root = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream_path = format!("ws://{}/streaming/", listener.local_addr().unwrap());
+        {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                while let Ok((tcp, _)) = listener.accept().await {
+                    tokio::spawn(stream(tcp, Arc::clone(&state)));
+                }
+            });
+        }
+        info!("mock api={api_root} stream={stream_path}");
+        Self {
+            api_root,
+            stream_path,
+            state,
+        }
+    }
+
+    pub fn api_config(&self) -> ApiConfig {
+        ApiConfig {
+            api_root: self.api_root.clone(),
+            stream_path: self.stream_path.clone(),
+            auth_root: self.api_root.clone(),
+            charging_history_root: self.api_root.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// 使用初始token访问模拟服务的客户端
+    pub async fn api_client(&self) -> tesla_api::ApiClient {
+        let token = tesla_api::TokenState::from_token(
+            &self.api_config(),
+            tesla_api::AccessTokenResponse {
+                access_token: ACCESS_TOKEN.into(),
+                refresh_token: REFRESH_TOKEN.into(),
+                expires_in: 3600,
+                create_timestamp: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs() as i64),
+                ..Default::default()
+            },
+            String::new(),
+        )
+        .expect("token state");
+        tesla_api::ApiClient::init(&self.api_config(), Arc::new(tokio::sync::Mutex::new(token)))
+            .await
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// 修改运行中的场景, 如让车辆休眠
+    pub fn update(&self, f: impl FnOnce(&mut Scenario)) {
+        f(&mut self.state.lock().unwrap().scenario);
+    }
+}
+
+fn authorized(state: &mut MockState, token: &str) -> bool {
+    let ok = !state.scenario.token_expired && token == state.access_token;
+    if !ok {
+        state.stats.unauthorized += 1;
+    }
+    ok
+}
+
+/// 未授权时返回401
+fn unauthorized(state: &SharedState, headers: &HeaderMap) -> Option<Response> {
+    let token = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if authorized(&mut state.lock().unwrap(), token) {
+        None
+    } else {
+        Some((StatusCode::UNAUTHORIZED, "unauthorized").into_response())
+    }
+}
+
+/// 返回场景中排队的错误
+fn injected_failure(state: &SharedState) -> Option<Response> {
+    let mut state = state.lock().unwrap();
+    if state.scenario.failures.is_empty() {
+        return None;
+    }
+    let (code, retry_after) = state.scenario.failures.remove(0);
+    state.stats.failures += 1;
+    let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut resp = (status, "injected failure").into_response();
+    if let Some(secs) = retry_after {
+        resp.headers_mut()
+            .insert("Retry-After", secs.to_string().parse().unwrap());
+    }
+    Some(resp)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    refresh_token: String,
+}
+
+async fn token(State(state): State<SharedState>, Json(req): Json<TokenRequest>) -> Response {
+    let mut state = state.lock().unwrap();
+    if req.grant_type != "refresh_token" || req.refresh_token != REFRESH_TOKEN {
+        return (StatusCode::UNAUTHORIZED, "invalid refresh_token").into_response();
+    }
+    state.token_seq += 1;
+    state.access_token = format!("mock-access-{}", state.token_seq);
+    state.scenario.token_expired = false;
+    state.stats.token_refreshes += 1;
+    Json(json!({
+        "access_token": state.access_token,
+        "refresh_token": REFRESH_TOKEN,
+        "expires_in": state.scenario.expires_in,
+        "token_type": "Bearer",
+    }))
+    .into_response()
+}
+
+async fn users_me(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    if let Some(r) = unauthorized(&state, &headers).or_else(|| injected_failure(&state)) {
+        return r;
+    }
+    Json(json!({
+        "response": {
+            "email": "mock@example.com",
+            "full_name": "Mock",
+            "profile_image_url": "",
+        }
+    }))
+    .into_response()
+}
+
+async fn vehicles(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    if let Some(r) = unauthorized(&state, &headers).or_else(|| injected_failure(&state)) {
+        return r;
+    }
+    let vehicle = state.lock().unwrap().scenario.vehicle.clone();
+    Json(json!({ "response": [vehicle], "count": 1 })).into_response()
+}
+
+async fn vehicle_data(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Some(r) = unauthorized(&state, &headers).or_else(|| injected_failure(&state)) {
+        return r;
+    }
+    let mut state = state.lock().unwrap();
+    if id != state.scenario.vehicle.id {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    }
+    state.stats.vehicle_data += 1;
+    if state.scenario.asleep {
+        return (
+            StatusCode::REQUEST_TIMEOUT,
+            Json(json!({
+                "response": null,
+                "error": "vehicle unavailable: {:error=>\"vehicle unavailable:\"}",
+            })),
+        )
+            .into_response();
+    }
+    let snapshots = &state.scenario.snapshots;
+    let i = state.snapshot_index.min(snapshots.len().saturating_sub(1));
+    let d = snapshots.get(i).cloned().unwrap_or_default();
+    state.snapshot_index += 1;
+    Json(json!({ "response": d })).into_response()
+}
+
+async fn wake_up(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+) -> Response {
+    if let Some(r) = unauthorized(&state, &headers).or_else(|| injected_failure(&state)) {
+        return r;
+    }
+    let mut state = state.lock().unwrap();
+    if id != state.scenario.vehicle.id {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    }
+    state.stats.wake_ups += 1;
+    state.scenario.asleep = false;
+    state.scenario.vehicle.state = "online".into();
+    let v = &state.scenario.vehicle;
+    Json(json!({
+        "response": VehicleData {
+            id: v.id,
+            vehicle_id: v.vehicle_id,
+            vin: v.vin.clone(),
+            state: v.state.clone(),
+            ..Default::default()
+        }
+    }))
+    .into_response()
+}
+
+fn stream_message(msg_type: &str, tag: &str, value: &str, error_type: Option<&str>) -> Message {
+    Message::text(
+        json!({
+            "msg_type": msg_type,
+            "tag": tag,
+            "value": value,
+            "error_type": error_type,
+        })
+        .to_string(),
+    )
+}
+
+/// 按stream协议推送: 订阅 -> control:hello -> data:update..., 推送完发送vehicle_disconnected后关闭
+async fn stream(tcp: TcpStream, state: SharedState) {
+    let mut ws = match tokio_tungstenite::accept_async(tcp).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            error!("mock stream accept: {e}");
+            return;
+        }
+    };
+    let subscribe = match ws.next().await {
+        Some(Ok(msg)) if msg.is_text() => msg.into_text().unwrap_or_default(),
+        _ => return,
+    };
+    let subscribe: serde_json::Value = serde_json::from_str(&subscribe).unwrap_or_default();
+    let tag = subscribe["tag"].as_str().unwrap_or_default().to_string();
+    let token = subscribe["token"].as_str().unwrap_or_default();
+    let (authorized, asleep, interval, disconnect_after) = {
+        let mut state = state.lock().unwrap();
+        state.stats.stream_connections += 1;
+        (
+            authorized(&mut state, token),
+            state.scenario.asleep,
+            state.scenario.interval,
+            state.scenario.disconnect_after,
+        )
+    };
+    if !authorized {
+        let msg = stream_message(
+            "data:error",
+            &tag,
+            "Can't validate token. ",
+            Some("client_error"),
+        );
+        let _ = ws.send(msg).await;
+        let _ = ws.close(None).await;
+        return;
+    }
+    if asleep {
+        let msg = stream_message(
+            "data:error",
+            &tag,
+            "Vehicle is offline",
+            Some("vehicle_error"),
+        );
+        let _ = ws.send(msg).await;
+        let _ = ws.close(None).await;
+        return;
+    }
+    let hello = Message::text(
+        json!({"msg_type": "control:hello", "connection_timeout": 30000}).to_string(),
+    );
+    if ws.send(hello).await.is_err() {
+        return;
+    }
+    let mut sent = 0;
+    loop {
+        let ds = {
+            let mut state = state.lock().unwrap();
+            let ds = state.scenario.updates.get(state.update_index).cloned();
+            if ds.is_some() {
+                state.update_index += 1;
+            }
+            ds
+        };
+        let ds = match ds {
+            Some(ds) => ds,
+            None => break,
+        };
+        let value = format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            ds.timestamp,
+            ds.speed,
+            ds.odometer,
+            ds.soc,
+            ds.elevation,
+            ds.est_heading,
+            ds.est_lat,
+            ds.est_lng,
+            ds.power,
+            ds.shift_state,
+            ds.range,
+            ds.est_range,
+            ds.heading
+        );
+        if ws
+            .send(stream_message("data:update", &tag, &value, None))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        sent += 1;
+        if disconnect_after > 0 && sent >= disconnect_after {
+            // 不发送close帧, 模拟网络断开
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+    let msg = stream_message(
+        "data:error",
+        &tag,
+        "disconnected",
+        Some("vehicle_disconnected"),
+    );
+    let _ = ws.send(msg).await;
+    let _ = ws.close(None).await;
+}
@@ -0,0 +1,17 @@
+//! 单独运行模拟服务: cargo run -p tesla-mock -- drive
+use tesla_mock::{MockServer, Scenario};
+
+#[tokio::main]
+async fn main() {
+    base::init_logger();
+    let name = std::env::args().nth(1).unwrap_or_else(|| "drive".into());
+    let scenario =
+        Scenario::by_name(&name).expect("scenario: drive/charge/sleep/token_expiry/disconnects");
+    let server = MockServer::start(scenario).await;
+    // 作为AppConfig.api_config使用
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&server.api_config()).unwrap()
+    );
+    tokio::signal::ctrl_c().await.unwrap();
+}
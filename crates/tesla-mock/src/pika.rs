@@ -0,0 +1,114 @@
+//! 内存中的pika, 只支持hash表(HSET/HVALS/HKEYS), 其他读取返回空, 写入直接返回成功
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+type Tables = Arc<Mutex<BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>>>;
+
+pub struct FakePika {
+    /// redis://127.0.0.1:port/
+    pub address: String,
+    tables: Tables,
+}
+
+impl FakePika {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("redis://{}/", listener.local_addr().unwrap());
+        let tables = Tables::default();
+        {
+            let tables = Arc::clone(&tables);
+            tokio::spawn(async move {
+                while let Ok((tcp, _)) = listener.accept().await {
+                    tokio::spawn(serve(tcp, Arc::clone(&tables)));
+                }
+            });
+        }
+        Self { address, tables }
+    }
+
+    /// 已写入的hash表名
+    pub fn tables(&self) -> Vec<String> {
+        self.tables.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// 读取一行RESP头, 如*3或$5, 返回其中的数字; 连接关闭时返回None
+async fn read_len(tcp: &mut BufReader<TcpStream>) -> Option<usize> {
+    let mut line = String::new();
+    if tcp.read_line(&mut line).await.ok()? == 0 {
+        return None;
+    }
+    line.trim().get(1..)?.parse().ok()
+}
+
+/// 读取一条命令, 参数均为bulk string
+async fn read_command(tcp: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let n = read_len(tcp).await?;
+    let mut args = vec![];
+    for _ in 0..n {
+        let len = read_len(tcp).await?;
+        let mut b = vec![0; len + 2];
+        tcp.read_exact(&mut b).await.ok()?;
+        b.truncate(len);
+        args.push(b);
+    }
+    Some(args)
+}
+
+fn array(v: Vec<&Vec<u8>>) -> Vec<u8> {
+    let mut reply = format!("*{}\r\n", v.len()).into_bytes();
+    for b in v {
+        reply.extend(format!("${}\r\n", b.len()).into_bytes());
+        reply.extend(b);
+        reply.extend(b"\r\n");
+    }
+    reply
+}
+
+fn execute(tables: &Tables, args: &[Vec<u8>]) -> Vec<u8> {
+    let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let key = args
+        .get(1)
+        .map(|k| String::from_utf8_lossy(k).to_string())
+        .unwrap_or_default();
+    let mut tables = tables.lock().unwrap();
+    match cmd.as_str() {
+        "HSET" => {
+            let table = tables.entry(key).or_default();
+            for kv in args[2..].chunks(2) {
+                table.insert(kv[0].clone(), kv[1].clone());
+            }
+            b":1\r\n".to_vec()
+        }
+        "HVALS" => array(
+            tables
+                .get(&key)
+                .map(|t| t.values().collect())
+                .unwrap_or_default(),
+        ),
+        "HKEYS" => array(
+            tables
+                .get(&key)
+                .map(|t| t.keys().collect())
+                .unwrap_or_default(),
+        ),
+        "GET" | "HGET" => b"$-1\r\n".to_vec(),
+        "SET" | "PING" => b"+OK\r\n".to_vec(),
+        _ => b"*0\r\n".to_vec(),
+    }
+}
+
+async fn serve(tcp: TcpStream, tables: Tables) {
+    let mut tcp = BufReader::new(tcp);
+    while let Some(args) = read_command(&mut tcp).await {
+        if args.is_empty() {
+            return;
+        }
+        let reply = execute(&tables, &args);
+        if tcp.get_mut().write_all(&reply).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! 预置场景: 行驶, 充电, 休眠, token过期, stream断线
+use base::pb::tesla::*;
+use std::time::Duration;
+
+pub const ACCESS_TOKEN: &str = "mock-access-0";
+pub const REFRESH_TOKEN: &str = "mock-refresh";
+
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub vehicle: Vehicle,
+    /// vehicle_data依次返回, 最后一个重复返回
+    pub snapshots: Vec<VehicleData>,
+    /// stream依次推送, 断线重连后从断开处继续
+    pub updates: Vec<DrivingState>,
+    pub interval: Duration,
+    /// 每次连接推送多少条后直接断开TCP, 0为不断开
+    pub disconnect_after: usize,
+    pub asleep: bool,
+    /// 为true时初始access token已过期, refresh后才能访问
+    pub token_expired: bool,
+    pub expires_in: i64,
+    /// API请求依次返回的错误(状态码, Retry-After秒数), 用完后正常返回
+    pub failures: Vec<(u16, Option<u64>)>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            vehicle: Vehicle {
+                id: 1001,
+                vehicle_id: 2001,
+                vin: "LRW3E7EK0MC000001".into(),
+                display_name: "Mock".into(),
+                state: "online".into(),
+                api_version: 67,
+                ..Default::default()
+            },
+            snapshots: vec![],
+            updates: vec![],
+            interval: Duration::from_millis(10),
+            disconnect_after: 0,
+            asleep: false,
+            token_expired: false,
+            expires_in: 8 * 3600,
+            failures: vec![],
+        }
+    }
+}
+
+/// 起点附近的推送, 每条间隔1秒
+fn update(i: i64, speed: f64, power: f64, shift_state: &str) -> DrivingState {
+    DrivingState {
+        timestamp: 1700000000000 + i * 1000,
+        speed,
+        odometer: 1000.0 + speed * i as f64 / 3600.0,
+        soc: 80.0,
+        elevation: 10.0,
+        est_heading: 90.0,
+        heading: 90.0,
+        est_lat: 31.2304,
+        est_lng: 121.4737 + speed * i as f64 * 0.00001,
+        power,
+        shift_state: shift_state.into(),
+        range: 240.0,
+        est_range: 220.0,
+        ..Default::default()
+    }
+}
+
+impl Scenario {
+    pub fn snapshot(&self, f: impl FnOnce(&mut VehicleData)) -> VehicleData {
+        let mut d = VehicleData {
+            id: self.vehicle.id,
+            vehicle_id: self.vehicle.vehicle_id,
+            vin: self.vehicle.vin.clone(),
+            state: "online".into(),
+            charge_state: Some(VehicleChargeState {
+                battery_level: 80.0,
+                usable_battery_level: 80.0,
+                battery_range: 240.0,
+                charge_limit_soc: 90.0,
+                charging_state: "Disconnected".into(),
+                ..Default::default()
+            }),
+            climate_state: Some(VehicleClimateState {
+                inside_temp: 22.0,
+                outside_temp: 18.0,
+                ..Default::default()
+            }),
+            vehicle_state: Some(VehicleState {
+                car_version: "2023.44.30.8".into(),
+                odometer: 1000.0,
+                locked: true,
+                vehicle_name: self.vehicle.display_name.clone(),
+                tpms_pressure_fl: 2.9,
+                tpms_pressure_fr: 2.9,
+                tpms_pressure_rl: 2.9,
+                tpms_pressure_rr: 2.9,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        f(&mut d);
+        d
+    }
+
+    pub fn drive() -> Self {
+        let mut s = Self::default();
+        s.snapshots = vec![s.snapshot(|_| ())];
+        s.updates = (0..10).map(|i| update(i, 36.0, 15.0, "D")).collect();
+        s
+    }
+
+    pub fn charge() -> Self {
+        let mut s = Self::default();
+        s.snapshots = (0..=10)
+            .map(|i| {
+                s.snapshot(|d| {
+                    let cs = d.charge_state.as_mut().unwrap();
+                    cs.battery_level = 50.0 + i as f64 * 4.0;
+                    cs.usable_battery_level = cs.battery_level;
+                    cs.charge_energy_added = i as f64 * 3.0;
+                    if i < 10 {
+                        cs.charging_state = "Charging".into();
+                        cs.charger_power = 7.0;
+                    } else {
+                        cs.charging_state = "Complete".into();
+                    }
+                })
+            })
+            .collect();
+        s.updates = (0..5).map(|i| update(i, 0.0, -7.0, "")).collect();
+        s
+    }
+
+    pub fn sleep() -> Self {
+        let mut s = Self::drive();
+        s.asleep = true;
+        s.vehicle.state = "asleep".into();
+        s
+    }
+
+    pub fn token_expiry() -> Self {
+        let mut s = Self::drive();
+        s.token_expired = true;
+        s
+    }
+
+    /// 每推送3条断开一次
+    pub fn disconnects() -> Self {
+        let mut s = Self::drive();
+        s.disconnect_after = 3;
+        s
+    }
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "drive" => Self::drive(),
+            "charge" => Self::charge(),
+            "sleep" => Self::sleep(),
+            "token_expiry" => Self::token_expiry(),
+            "disconnects" => Self::disconnects(),
+            _ => return None,
+        })
+    }
+}